@@ -0,0 +1,106 @@
+use criterion::{criterion_group, criterion_main, BenchmarkGroup, Criterion, Throughput};
+use criterion::measurement::WallTime;
+
+use chip8::cpu::{Chip8Machine, Machine, INSTRUCTIONS_PER_FRAME};
+
+use std::env;
+use std::fs;
+
+// Frames run per iteration, 100 000 instructions
+const FRAMES: u64 = 12_500;
+
+// Endless loop mixing register arithmetic, skips and jumps
+const ARITHMETIC_LOOP: [u8; 16] = [
+    0x60, 0x00, // V0 = 0
+    0x61, 0x03, // V1 = 3
+    0x80, 0x14, // V0 += V1
+    0x82, 0x05, // V2 -= V0
+    0x83, 0x0E, // V3 <<= 1
+    0x30, 0xFF, // skip if V0 == 0xFF
+    0x12, 0x04, // jump 0x204
+    0x12, 0x00, // jump 0x200
+];
+
+// Draws a 15 row sprite diagonally across the screen forever
+const DISPLAY_LOOP: [u8; 31] = [
+    0x60, 0x00, // V0 = 0
+    0x61, 0x00, // V1 = 0
+    0xA2, 0x10, // I = 0x210
+    0xD0, 0x1F, // draw 15 rows at (V0, V1)
+    0x70, 0x03, // V0 += 3
+    0x71, 0x01, // V1 += 1
+    0x12, 0x06, // jump 0x206
+    0x00, 0x00,
+    0xFF, 0x81, 0xBD, 0xA5, 0xA5, 0xBD, 0x81, 0xFF, 0x81, 0xBD, 0xA5, 0xA5, 0xBD, 0x81, 0xFF,
+];
+
+// Counts up and redraws the count with a subroutine, like a game's score
+const SCORE_COUNTER: [u8; 44] = [
+    0x63, 0x00, // V3 = 0
+    0x22, 0x0A, // call 0x20A
+    0x73, 0x01, // V3 += 1
+    0x12, 0x02, // jump 0x202
+    0x00, 0x00,
+    0x00, 0xE0, // clear the screen
+    0xA3, 0x00, // I = 0x300
+    0xF3, 0x33, // BCD of V3
+    0xF2, 0x65, // V0..V2 = digits
+    0x64, 0x00, // V4 = 0
+    0x65, 0x00, // V5 = 0
+    0xF0, 0x29, // I = digit V0
+    0xD4, 0x55, // draw at (V4, V5)
+    0x74, 0x05, // V4 += 5
+    0xF1, 0x29, // I = digit V1
+    0xD4, 0x55, // draw at (V4, V5)
+    0x74, 0x05, // V4 += 5
+    0xF2, 0x29, // I = digit V2
+    0xD4, 0x55, // draw at (V4, V5)
+    0xF6, 0x15, // delay timer = V6
+    0xE0, 0x9E, // skip if key V0 is pressed
+    0x00, 0xEE, // return
+];
+
+fn bench_rom(group: &mut BenchmarkGroup<WallTime>, name: &str, rom: &[u8]) {
+    group.bench_function(name, |b| {
+        let mut m = Machine::new();
+        m.load_rom(rom).unwrap();
+        b.iter(|| {
+            for _ in 0..FRAMES {
+                for _ in 0..INSTRUCTIONS_PER_FRAME {
+                    // A faulting ROM would be measured as a tight loop going nowhere
+                    if let Err(why) = m.run([false; 16]) {
+                        panic!("{} faulted at {:#05x}: {}", name, m.pc, why);
+                    }
+                }
+                m.tick_timers();
+                m.dirty = None;
+            }
+        })
+    });
+}
+
+fn instructions_per_second(c: &mut Criterion) {
+    let mut group = c.benchmark_group("execution");
+    group.throughput(Throughput::Elements(FRAMES * INSTRUCTIONS_PER_FRAME as u64));
+    bench_rom(&mut group, "arithmetic loop", &ARITHMETIC_LOOP);
+    bench_rom(&mut group, "display loop", &DISPLAY_LOOP);
+    bench_rom(&mut group, "score counter", &SCORE_COUNTER);
+
+    // Real ROMs, from a directory of *.ch8 files like chip8-test takes
+    if let Ok(dir) = env::var("CHIP8_BENCH_ROMS") {
+        let mut roms: Vec<_> = fs::read_dir(&dir)
+            .expect("CHIP8_BENCH_ROMS is not a readable directory")
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|e| e.eq_ignore_ascii_case("ch8")))
+            .collect();
+        roms.sort();
+        for path in roms {
+            let rom = fs::read(&path).unwrap();
+            bench_rom(&mut group, &path.file_stem().unwrap().to_string_lossy(), &rom);
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, instructions_per_second);
+criterion_main!(benches);
@@ -0,0 +1,23 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use chip8::cpu::{Chip8Machine, Machine, OpCodes};
+use chip8::instruction::{Chip8Instruction, DecodableInstruction, Opcode};
+use chip8::quirks::PRESETS;
+
+// Input layout: the opcode, a quirk preset index, I and the 16 registers,
+// so single instructions are executed from arbitrary machine states.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 21 {
+        return;
+    }
+    let mut m = Machine::new();
+    m.quirks = PRESETS[data[2] as usize % PRESETS.len()].1;
+    m.i = u16::from_le_bytes([data[3], data[4]]);
+    m.registers.copy_from_slice(&data[5..21]);
+
+    let instruction = Chip8Instruction::new(data[0], data[1]);
+    assert_eq!(instruction.raw, u16::from_be_bytes([data[0], data[1]]));
+    m.execute(Opcode::decode(instruction.raw));
+    assert!(m.pc <= 0xFFF);
+});
@@ -0,0 +1,46 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use chip8::cpu::{Chip8Machine, Machine, INSTRUCTIONS_PER_FRAME};
+use chip8::quirks::PRESETS;
+
+// Two seconds of emulation
+const FRAMES: usize = 120;
+
+// Input layout: a quirk preset index, a count n, n 16 bit keypad masks
+// cycled through frame by frame, then the ROM itself.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let quirks = PRESETS[data[0] as usize % PRESETS.len()].1;
+    let key_bytes = (data[1] as usize * 2).min(data.len() - 2);
+    let keys: Vec<u16> = data[2..2 + key_bytes]
+        .chunks_exact(2)
+        .map(|k| u16::from_le_bytes([k[0], k[1]]))
+        .collect();
+    let rom = &data[2 + key_bytes..];
+
+    let mut m = Machine::new();
+    m.quirks = quirks;
+    if m.load_rom(rom).is_err() {
+        return;
+    }
+
+    for frame in 0..FRAMES {
+        let mask = if keys.is_empty() { 0 } else { keys[frame % keys.len()] };
+        let mut keypad = [false; 16];
+        for (i, key) in keypad.iter_mut().enumerate() {
+            *key = mask & (1 << i) != 0;
+        }
+
+        for _ in 0..INSTRUCTIONS_PER_FRAME {
+            if m.run(keypad).is_err() {
+                return;
+            }
+            assert!(m.pc <= 0xFFF);
+            assert!(m.stack.len() <= m.quirks.stack_depth);
+        }
+        m.tick_timers();
+    }
+});
@@ -0,0 +1,73 @@
+use chip8::dap::{self, DebugSession};
+
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The machine runs at 60 frames per second between requests
+const FRAME: Duration = Duration::from_micros(16_667);
+
+// A Debug Adapter Protocol server on stdin and stdout, for editors to launch
+// and debug ROMs with. Anything else it has to say goes to stderr.
+fn main() {
+    let (requests, received) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        loop {
+            match dap::read_message(&mut stdin) {
+                Ok(Some(message)) => {
+                    if requests.send(message).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(why) => {
+                    eprintln!("Couldn't read a request: {}", why);
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut session = DebugSession::new();
+    let mut next_frame = Instant::now();
+    let stdout = io::stdout();
+    while !session.is_finished() {
+        let message = if session.is_running() {
+            match received.recv_timeout(next_frame.saturating_duration_since(Instant::now())) {
+                Ok(message) => Some(message),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        } else {
+            match received.recv() {
+                Ok(message) => Some(message),
+                Err(_) => break,
+            }
+        };
+
+        match message {
+            Some(message) => {
+                let was_running = session.is_running();
+                session.handle(&message);
+                if !was_running {
+                    next_frame = Instant::now();
+                }
+            }
+            None => {
+                session.run_frame();
+                next_frame += FRAME;
+            }
+        }
+
+        let mut stdout = stdout.lock();
+        for message in session.take_messages() {
+            if let Err(why) = dap::write_message(&mut stdout, &message) {
+                eprintln!("Couldn't send a response: {}", why);
+                return;
+            }
+        }
+    }
+}
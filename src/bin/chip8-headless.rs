@@ -0,0 +1,160 @@
+use chip8::capture;
+use chip8::cpu;
+use chip8::cpu::Chip8Machine;
+use chip8::database::Database;
+use chip8::external;
+use chip8::palette::Palette;
+use chip8::quirks::Quirks;
+use chip8::replay::Replay;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const USAGE: &str = "Usage: chip8-headless [--frames <n>] [--replay <file>] [--record <file.gif|dir>] \
+                     [--audio <file.wav>] [--screenshot <file.png>] [--scale <n>] [--palette <name>] [--quirks <preset>] \
+                     [--database <programs.json>] <path to ROM>\n\
+                     --audio records the beeper only, ROMs playing XO-CHIP audio patterns stop with an error";
+
+struct Options {
+    rom_path: String,
+    frames: Option<u64>,
+    replay: Option<PathBuf>,
+    record: Option<PathBuf>,
+    audio: Option<PathBuf>,
+    screenshot: Option<PathBuf>,
+    scale: usize,
+    // Unless given, the database's settings for the ROM are used
+    palette: Option<Palette>,
+    quirks: Option<Quirks>,
+    database: Option<PathBuf>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            rom_path: String::new(),
+            frames: None,
+            replay: None,
+            record: None,
+            audio: None,
+            screenshot: None,
+            scale: 4,
+            palette: None,
+            quirks: None,
+            database: None,
+        };
+
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} expects a value", arg));
+            match arg.as_str() {
+                "--frames" => options.frames = Some(value()?.parse().map_err(|_| "--frames expects a number")?),
+                "--replay" => options.replay = Some(PathBuf::from(value()?)),
+                "--record" => options.record = Some(PathBuf::from(value()?)),
+                "--audio" => options.audio = Some(PathBuf::from(value()?)),
+                "--screenshot" => options.screenshot = Some(PathBuf::from(value()?)),
+                "--scale" => options.scale = value()?.parse().map_err(|_| "--scale expects a number")?,
+                "--palette" => {
+                    let name = value()?;
+                    options.palette = Some(Palette::by_name(name).ok_or(format!("Unknown palette {}", name))?);
+                }
+                "--quirks" => {
+                    let name = value()?;
+                    options.quirks = Some(Quirks::by_name(name).ok_or(format!("Unknown quirk preset {}", name))?);
+                }
+                "--database" => options.database = Some(PathBuf::from(value()?)),
+                _ => options.rom_path = arg.clone(),
+            }
+        }
+
+        if options.rom_path.is_empty() {
+            return Err(String::from(USAGE));
+        }
+        Ok(options)
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let replay = match options.replay {
+        Some(ref path) => {
+            let text = fs::read_to_string(path).map_err(|why| format!("Couldn't read {}: {}", path.display(), why))?;
+            Replay::parse(&text)?
+        }
+        None => Replay::default(),
+    };
+    // Without a frame count, run one second past the last input
+    let frames = options.frames.unwrap_or(replay.last_frame() + 60);
+
+    let database = match options.database {
+        Some(ref path) => Database::load(path)?,
+        None => Database::bundled(),
+    };
+    // Nobody is there to choose among the ROMs of an archive
+    let rom = external::load_rom(&options.rom_path, &database, |_| None)?;
+    let metadata = rom.metadata.unwrap_or_default();
+    if !metadata.title.is_empty() {
+        println!("{}", metadata.credits());
+    }
+    let palette = options.palette.or(metadata.palette).unwrap_or_default();
+    let tickrate = metadata.tickrate.unwrap_or(cpu::INSTRUCTIONS_PER_FRAME);
+
+    let mut chip8_machine: cpu::Machine = cpu::Machine::new();
+    chip8_machine.quirks = options.quirks.or(metadata.quirks).unwrap_or_default();
+    chip8_machine.load_rom(&rom.bytes)?;
+
+    let mut recorder = match options.record {
+        Some(ref path) => Some(capture::Recorder::start(path, &palette, options.scale)?),
+        None => None,
+    };
+
+    let mut audio = match options.audio {
+        Some(ref path) => Some(capture::WavRecorder::start(path)?),
+        None => None,
+    };
+
+    for frame in 0..frames {
+        let keypad = replay.keys_at(frame);
+        for _ in 0..tickrate {
+            let opcode = chip8_machine.run(keypad)
+                .map_err(|why| format!("Emulation stopped at {:#05x} in frame {}: {}", chip8_machine.pc, frame, why))?;
+            if let Some(ref audio) = audio {
+                audio.check_opcode(opcode)?;
+            }
+        }
+        if let Some(ref mut audio) = audio {
+            audio.add_frame(chip8_machine.sound_timer > 0)?;
+        }
+        chip8_machine.tick_timers();
+        chip8_machine.dirty = None;
+
+        if let Some(ref mut recorder) = recorder {
+            recorder.add_frame(&chip8_machine.screen)?;
+        }
+    }
+
+    if let Some(recorder) = recorder {
+        recorder.finish()?;
+    }
+    if let Some(audio) = audio {
+        audio.finish()?;
+    }
+    if let Some(ref path) = options.screenshot {
+        let file = fs::File::create(path).map_err(|why| format!("Couldn't create {}: {}", path.display(), why))?;
+        capture::write_png(&chip8_machine.screen, &palette, options.scale, file)
+            .map_err(|why| format!("Couldn't write {}: {}", path.display(), why))?;
+    }
+
+    println!("Ran {} frames of {}", frames, Path::new(&options.rom_path).display());
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let result = Options::parse(&args).and_then(|options| run(&options));
+    if let Err(why) = result {
+        eprintln!("{}", why);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,196 @@
+use chip8::capture;
+use chip8::cpu;
+use chip8::cpu::Chip8Machine;
+use chip8::palette::Palette;
+use chip8::quirks::{Quirks, PRESETS};
+use chip8::replay::Replay;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const USAGE: &str = "Usage: chip8-test [--frames <n>] [--quirks <preset>]... [--update] <directory of ROMs>";
+
+// Long enough for the usual test ROMs to draw their results
+const DEFAULT_FRAMES: u64 = 300;
+
+struct Options {
+    dir: PathBuf,
+    frames: u64,
+    presets: Vec<(&'static str, Quirks)>,
+    update: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut dir = None;
+        let mut frames = DEFAULT_FRAMES;
+        let mut presets = Vec::new();
+        let mut update = false;
+
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--frames" => {
+                    frames = args.next().and_then(|f| f.parse().ok()).ok_or("--frames expects a number")?;
+                }
+                "--quirks" => {
+                    let name = args.next().ok_or("--quirks expects a preset name")?;
+                    let preset = PRESETS.iter().find(|(n, _)| n == name).ok_or(format!("Unknown quirk preset {}", name))?;
+                    presets.push(*preset);
+                }
+                "--update" => update = true,
+                _ => dir = Some(PathBuf::from(arg)),
+            }
+        }
+
+        if presets.is_empty() {
+            presets.extend_from_slice(&PRESETS);
+        }
+        Ok(Options { dir: dir.ok_or(USAGE)?, frames, presets, update })
+    }
+}
+
+enum Outcome {
+    Pass,
+    Fail,
+    // No expected image stored yet
+    Missing,
+    Updated,
+    Error(String),
+}
+
+impl Outcome {
+    fn label(&self) -> &str {
+        match self {
+            Outcome::Pass => "pass",
+            Outcome::Fail => "FAIL",
+            Outcome::Missing => "missing",
+            Outcome::Updated => "updated",
+            Outcome::Error(_) => "ERROR",
+        }
+    }
+}
+
+/// Runs `rom` headlessly, driven by `<rom>.replay` when it exists, and
+/// returns the final screen.
+fn run_rom(rom: &Path, quirks: Quirks, frames: u64) -> Result<[[bool; 32]; 64], String> {
+    let bytes = fs::read(rom).map_err(|why| format!("Couldn't read {}: {}", rom.display(), why))?;
+    let replay = match fs::read_to_string(rom.with_extension("replay")) {
+        Ok(text) => Replay::parse(&text)?,
+        Err(_) => Replay::default(),
+    };
+
+    let mut chip8_machine: cpu::Machine = cpu::Machine::new();
+    chip8_machine.quirks = quirks;
+    chip8_machine.load_rom(&bytes)?;
+
+    for frame in 0..frames {
+        let keypad = replay.keys_at(frame);
+        for _ in 0..cpu::INSTRUCTIONS_PER_FRAME {
+            chip8_machine.run(keypad)
+                .map_err(|why| format!("{} stopped at {:#05x}: {}", rom.display(), chip8_machine.pc, why))?;
+        }
+        chip8_machine.tick_timers();
+    }
+    Ok(chip8_machine.screen)
+}
+
+fn check(rom: &Path, preset: &str, quirks: Quirks, options: &Options) -> Outcome {
+    let screen = match run_rom(rom, quirks, options.frames) {
+        Ok(screen) => screen,
+        Err(why) => return Outcome::Error(why),
+    };
+
+    let name = format!("{}.{}.png", capture::rom_name(&rom.to_string_lossy()), preset);
+    let expected_path = options.dir.join("expected").join(name);
+    let palette = Palette::default();
+
+    if options.update {
+        let written = fs::create_dir_all(options.dir.join("expected"))
+            .and_then(|_| fs::File::create(&expected_path))
+            .map_err(|why| why.to_string())
+            .and_then(|file| capture::write_png(&screen, &palette, 1, file).map_err(|why| why.to_string()));
+        return match written {
+            Ok(()) => Outcome::Updated,
+            Err(why) => Outcome::Error(format!("Couldn't write {}: {}", expected_path.display(), why)),
+        };
+    }
+
+    let file = match fs::File::open(&expected_path) {
+        Ok(file) => file,
+        Err(_) => return Outcome::Missing,
+    };
+    match capture::read_png(file, &palette) {
+        Ok(expected) if expected == screen => Outcome::Pass,
+        Ok(_) => Outcome::Fail,
+        Err(why) => Outcome::Error(format!("Couldn't read {}: {}", expected_path.display(), why)),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(why) => {
+            eprintln!("{}", why);
+            process::exit(2);
+        }
+    };
+
+    let mut roms: Vec<PathBuf> = match fs::read_dir(&options.dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|e| e.eq_ignore_ascii_case("ch8")))
+            .collect(),
+        Err(why) => {
+            eprintln!("Couldn't read {}: {}", options.dir.display(), why);
+            process::exit(2);
+        }
+    };
+    roms.sort();
+
+    let width = roms.iter().map(|rom| capture::rom_name(&rom.to_string_lossy()).len()).max().unwrap_or(0).max(3);
+    print!("{:width$}", "ROM", width = width);
+    for (preset, _) in options.presets.iter() {
+        print!("  {:8}", preset);
+    }
+    println!();
+
+    let mut failures = 0;
+    // Missing expected images fail the run too, or it would pass having
+    // compared nothing. `--update` stores them instead
+    let mut missing = 0;
+    let mut errors = Vec::new();
+    for rom in roms.iter() {
+        print!("{:width$}", capture::rom_name(&rom.to_string_lossy()), width = width);
+        for &(preset, quirks) in options.presets.iter() {
+            let outcome = check(rom, preset, quirks, &options);
+            print!("  {:8}", outcome.label());
+            match outcome {
+                Outcome::Fail => failures += 1,
+                Outcome::Missing => missing += 1,
+                Outcome::Error(why) => {
+                    failures += 1;
+                    errors.push(why);
+                }
+                _ => {}
+            }
+        }
+        println!();
+    }
+
+    for why in errors.iter() {
+        eprintln!("{}", why);
+    }
+    if failures > 0 {
+        println!("{} failed", failures);
+    }
+    if missing > 0 {
+        println!("{} missing, store their expected images with --update", missing);
+    }
+    if failures > 0 || missing > 0 {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,63 @@
+use chip8::cpu;
+use chip8::cpu::Chip8Machine;
+use chip8::database::Database;
+use chip8::external;
+
+use std::env;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// One 60 Hz frame; the screen is drawn and timers tick once per frame
+const FRAME_DURATION: Duration = Duration::from_micros(16_667);
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let file_path = args.get(1).expect("Usage: chip8-tui <path to ROM>");
+    let rom = match external::load_rom(file_path, &Database::bundled(), external::prompt_rom) {
+        Ok(rom) => rom,
+        Err(why) => {
+            eprintln!("{}", why);
+            process::exit(1);
+        }
+    };
+    let metadata = rom.metadata.unwrap_or_default();
+
+    let mut chip8_machine: cpu::Machine = cpu::Machine::new();
+    chip8_machine.quirks = metadata.quirks.unwrap_or_default();
+    if let Err(why) = chip8_machine.load_rom(&rom.bytes) {
+        eprintln!("Couldn't load {}: {}", file_path, why);
+        process::exit(1);
+    }
+    let tickrate = metadata.tickrate.unwrap_or(cpu::INSTRUCTIONS_PER_FRAME);
+
+    let mut terminal = external::Terminal::new().expect("Couldn't set up the terminal");
+
+    let mut error = None;
+    'emulation: while let Some(keypad) = terminal.poll() {
+        let frame_start = Instant::now();
+
+        for _ in 0..tickrate {
+            if let Err(why) = chip8_machine.run(keypad) {
+                error = Some(why);
+                break 'emulation;
+            }
+        }
+        chip8_machine.tick_timers();
+
+        let dirty = chip8_machine.dirty.take();
+        if terminal.draw(&chip8_machine, dirty).is_err() {
+            break;
+        }
+
+        if let Some(remaining) = FRAME_DURATION.checked_sub(frame_start.elapsed()) {
+            thread::sleep(remaining);
+        }
+    }
+
+    // Restore the terminal before reporting anything
+    drop(terminal);
+    if let Some(why) = error {
+        eprintln!("Emulation stopped at {:#05x}: {}", chip8_machine.pc, why);
+    }
+}
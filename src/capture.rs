@@ -0,0 +1,285 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cpu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::palette::Palette;
+
+/// Renders `screen` as RGB24 rows, each emulated pixel becoming a `scale` x
+/// `scale` square in the colors of `palette`.
+pub fn render_rgb(screen: &[[bool; 32]; 64], palette: &Palette, scale: usize) -> Vec<u8> {
+    let width = SCREEN_WIDTH * scale;
+    let mut rgb = Vec::with_capacity(width * SCREEN_HEIGHT * scale * 3);
+    for y in 0..SCREEN_HEIGHT * scale {
+        for x in 0..width {
+            let lit = screen[x / scale][y / scale];
+            rgb.extend_from_slice(&palette.color(if lit { 1.0 } else { 0.0 }));
+        }
+    }
+    rgb
+}
+
+/// Encodes `screen` as a PNG image, at native resolution when `scale` is 1.
+pub fn write_png<W: Write>(screen: &[[bool; 32]; 64], palette: &Palette, scale: usize, writer: W) -> Result<(), png::EncodingError> {
+    let mut encoder = png::Encoder::new(writer, (SCREEN_WIDTH * scale) as u32, (SCREEN_HEIGHT * scale) as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&render_rgb(screen, palette, scale))
+}
+
+/// Decodes a PNG written by `write_png` at any scale back into a screen,
+/// pixels in the foreground color of `palette` being lit.
+pub fn read_png<R: Read>(reader: R, palette: &Palette) -> Result<[[bool; 32]; 64], String> {
+    let mut reader = png::Decoder::new(reader).read_info().map_err(|why| why.to_string())?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(|why| why.to_string())?;
+
+    let channels = match (info.color_type, info.bit_depth) {
+        (png::ColorType::Rgb, png::BitDepth::Eight) => 3,
+        (png::ColorType::Rgba, png::BitDepth::Eight) => 4,
+        _ => return Err(String::from("Only 8 bit RGB and RGBA images are supported")),
+    };
+    let scale = info.width as usize / SCREEN_WIDTH;
+    if scale == 0 || info.width as usize != SCREEN_WIDTH * scale || info.height as usize != SCREEN_HEIGHT * scale {
+        return Err(format!("Unexpected image size {}x{}", info.width, info.height));
+    }
+
+    let mut screen = [[false; 32]; 64];
+    for (x, col) in screen.iter_mut().enumerate() {
+        for (y, pixel) in col.iter_mut().enumerate() {
+            let offset = y * scale * info.line_size + x * scale * channels;
+            *pixel = buffer[offset..offset + 3] == palette.foreground;
+        }
+    }
+    Ok(screen)
+}
+
+/// Saves a screenshot to the first free `<rom>-<frame>-<n>.png` in `dir`
+/// and returns its path.
+pub fn save_screenshot(
+    screen: &[[bool; 32]; 64],
+    palette: &Palette,
+    scale: usize,
+    dir: &Path,
+    rom_path: &str,
+    frame: u64,
+) -> Result<PathBuf, String> {
+    let path = numbered_path(dir, &format!("{}-{}", rom_name(rom_path), frame), "png");
+    let file = File::create(&path).map_err(|why| format!("Couldn't create {}: {}", path.display(), why))?;
+    write_png(screen, palette, scale, BufWriter::new(file))
+        .map_err(|why| format!("Couldn't write {}: {}", path.display(), why))?;
+    Ok(path)
+}
+
+/// File name of the ROM without directory and extension, to name captures.
+pub fn rom_name(rom_path: &str) -> String {
+    Path::new(rom_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from("chip8"))
+}
+
+/// `<dir>/<prefix>-<n>.<extension>` for the lowest `n` not taken yet.
+pub fn numbered_path(dir: &Path, prefix: &str, extension: &str) -> PathBuf {
+    (1..)
+        .map(|n| dir.join(format!("{}-{}.{}", prefix, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+// GIF delays are in hundredths of a second and browsers slow down frames
+// shorter than 2, so frames lasting less are dropped; GIFs end up with at
+// most 50 distinct frames per second while keeping the 60 Hz timing.
+const MIN_GIF_DELAY: u64 = 2;
+
+// Hundredths of a second between the starts of 60 Hz frames `start` and `end`
+fn centiseconds(start: u64, end: u64) -> u64 {
+    end * 100 / 60 - start * 100 / 60
+}
+
+enum Sink {
+    Gif(gif::Encoder<BufWriter<File>>),
+    // Directory receiving one numbered PPM image per frame
+    Frames(PathBuf),
+}
+
+/// Records the screen at 60 frames per second, either to an animated GIF or,
+/// when the path is not a `.gif` file, to a directory of numbered PPM
+/// images that ffmpeg reads with `-framerate 60 -i frame-%06d.ppm`.
+pub struct Recorder {
+    sink: Sink,
+    palette: Palette,
+    scale: usize,
+    frames: u64,
+    // Last GIF frame, held back until it is known how long it lasts
+    pending: Option<([[bool; 32]; 64], u64)>,
+}
+
+impl Recorder {
+    pub fn start(path: &Path, palette: &Palette, scale: usize) -> Result<Self, String> {
+        let sink = if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("gif")) {
+            let file = File::create(path).map_err(|why| format!("Couldn't create {}: {}", path.display(), why))?;
+            let colors = [palette.background, palette.foreground].concat();
+            let mut encoder = gif::Encoder::new(
+                BufWriter::new(file),
+                (SCREEN_WIDTH * scale) as u16,
+                (SCREEN_HEIGHT * scale) as u16,
+                &colors,
+            ).map_err(|why| format!("Couldn't write {}: {}", path.display(), why))?;
+            encoder.set_repeat(gif::Repeat::Infinite).map_err(|why| format!("Couldn't write {}: {}", path.display(), why))?;
+            Sink::Gif(encoder)
+        } else {
+            std::fs::create_dir_all(path).map_err(|why| format!("Couldn't create {}: {}", path.display(), why))?;
+            Sink::Frames(path.to_path_buf())
+        };
+
+        Ok(Recorder { sink, palette: *palette, scale, frames: 0, pending: None })
+    }
+
+    /// Adds the screen shown during the next 60 Hz frame.
+    pub fn add_frame(&mut self, screen: &[[bool; 32]; 64]) -> Result<(), String> {
+        self.frames += 1;
+        match self.sink {
+            Sink::Gif(_) => {
+                let now = self.frames - 1;
+                match self.pending.take() {
+                    // Identical frames are merged into a longer one
+                    Some((pending, start)) if pending == *screen => {
+                        self.pending = Some((pending, start));
+                        Ok(())
+                    }
+                    // Too short for GIF timing, replaced by the new frame
+                    Some((_, start)) if centiseconds(start, now) < MIN_GIF_DELAY => {
+                        self.pending = Some((*screen, start));
+                        Ok(())
+                    }
+                    Some((pending, start)) => {
+                        self.pending = Some((*screen, now));
+                        self.write_gif_frame(&pending, start, now)
+                    }
+                    None => {
+                        self.pending = Some((*screen, now));
+                        Ok(())
+                    }
+                }
+            }
+            Sink::Frames(ref dir) => {
+                let path = dir.join(format!("frame-{:06}.ppm", self.frames));
+                let mut ppm = format!("P6\n{} {}\n255\n", SCREEN_WIDTH * self.scale, SCREEN_HEIGHT * self.scale).into_bytes();
+                ppm.extend(render_rgb(screen, &self.palette, self.scale));
+                std::fs::write(&path, ppm).map_err(|why| format!("Couldn't write {}: {}", path.display(), why))
+            }
+        }
+    }
+
+    /// Number of frames recorded so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Writes out what is still buffered and closes the recording.
+    pub fn finish(mut self) -> Result<(), String> {
+        if let Some((pending, start)) = self.pending.take() {
+            self.write_gif_frame(&pending, start, self.frames)?;
+        }
+        if let Sink::Gif(encoder) = self.sink {
+            encoder.into_inner().map_err(|why| format!("Couldn't finish recording: {}", why))?;
+        }
+        Ok(())
+    }
+
+    // Writes a frame shown from 60 Hz frame `start` until `end`
+    fn write_gif_frame(&mut self, screen: &[[bool; 32]; 64], start: u64, end: u64) -> Result<(), String> {
+        let delay = centiseconds(start, end).min(u16::MAX as u64) as u16;
+        let width = SCREEN_WIDTH * self.scale;
+        let mut indices = Vec::with_capacity(width * SCREEN_HEIGHT * self.scale);
+        for y in 0..SCREEN_HEIGHT * self.scale {
+            for x in 0..width {
+                indices.push(screen[x / self.scale][y / self.scale] as u8);
+            }
+        }
+
+        let frame = gif::Frame {
+            width: width as u16,
+            height: (SCREEN_HEIGHT * self.scale) as u16,
+            delay,
+            buffer: std::borrow::Cow::Owned(indices),
+            ..gif::Frame::default()
+        };
+        if let Sink::Gif(ref mut encoder) = self.sink {
+            encoder.write_frame(&frame).map_err(|why| format!("Couldn't write frame: {}", why))?;
+        }
+        Ok(())
+    }
+}
+
+const SAMPLE_RATE: u32 = 44_100;
+const SAMPLES_PER_FRAME: u32 = SAMPLE_RATE / 60;
+const BEEP_FREQUENCY: u32 = 440;
+const BEEP_AMPLITUDE: i16 = 8_000;
+
+/// Records the beeper as a 16 bit mono WAV file, one 60 Hz frame at a time
+/// so the audio stays in sync with the emulated frames.
+pub struct WavRecorder {
+    writer: BufWriter<File>,
+    samples: u32,
+}
+
+impl WavRecorder {
+    pub fn start(path: &Path) -> Result<Self, String> {
+        let file = File::create(path).map_err(|why| format!("Couldn't create {}: {}", path.display(), why))?;
+        let mut recorder = WavRecorder { writer: BufWriter::new(file), samples: 0 };
+        // Sizes are filled in by finish, once they are known
+        recorder.write_header().map_err(|why| format!("Couldn't write {}: {}", path.display(), why))?;
+        Ok(recorder)
+    }
+
+    /// Fails on the XO-CHIP audio instructions, `F002` loading a sound
+    /// pattern and `FX3A` setting its pitch: only the beeper is recorded, so
+    /// the audio of a ROM using them would come out wrong.
+    pub fn check_opcode(&self, opcode: u16) -> Result<(), String> {
+        if opcode == 0xF002 || opcode & 0xF0FF == 0xF03A {
+            return Err(format!("Couldn't record audio: {:04X} plays XO-CHIP audio patterns, only the beeper can be recorded", opcode));
+        }
+        Ok(())
+    }
+
+    /// Adds a frame of square wave while `beeping`, silence otherwise.
+    pub fn add_frame(&mut self, beeping: bool) -> Result<(), String> {
+        let half_period = SAMPLE_RATE / BEEP_FREQUENCY / 2;
+        for _ in 0..SAMPLES_PER_FRAME {
+            let sample = match (beeping, (self.samples / half_period) % 2) {
+                (false, _) => 0,
+                (true, 0) => BEEP_AMPLITUDE,
+                (true, _) => -BEEP_AMPLITUDE,
+            };
+            self.writer.write_all(&sample.to_le_bytes()).map_err(|why| format!("Couldn't write audio: {}", why))?;
+            self.samples += 1;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        self.writer.seek(SeekFrom::Start(0)).map_err(|why| format!("Couldn't finish audio: {}", why))?;
+        self.write_header().map_err(|why| format!("Couldn't finish audio: {}", why))?;
+        self.writer.flush().map_err(|why| format!("Couldn't finish audio: {}", why))
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let data_size = self.samples * 2;
+        let w = &mut self.writer;
+        w.write_all(b"RIFF")?;
+        w.write_all(&(36 + data_size).to_le_bytes())?;
+        w.write_all(b"WAVEfmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;
+        w.write_all(&1u16.to_le_bytes())?; // PCM
+        w.write_all(&1u16.to_le_bytes())?; // mono
+        w.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        w.write_all(&(SAMPLE_RATE * 2).to_le_bytes())?;
+        w.write_all(&2u16.to_le_bytes())?;
+        w.write_all(&16u16.to_le_bytes())?;
+        w.write_all(b"data")?;
+        w.write_all(&data_size.to_le_bytes())
+    }
+}
@@ -0,0 +1,135 @@
+use serde_json::Value;
+
+use crate::database::Metadata;
+use crate::palette::Palette;
+use crate::quirks::Quirks;
+
+/// Octo cartridges are GIF images, recognised by the GIF signature.
+pub fn is_cartridge(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a")
+}
+
+/// A game exported by Octo as a cartridge: a GIF label whose pixels hide
+/// the program and the settings it was written with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cartridge {
+    /// Octo assembly source
+    pub program: String,
+    /// The embedded tickrate, quirks and colors
+    pub metadata: Metadata,
+}
+
+impl Cartridge {
+    /// Extracts the payload of a cartridge. Every color index of the GIF
+    /// frames carries 2 bits of it, the first index the most significant
+    /// ones. The payload is a 32-bit big-endian length followed by that many
+    /// bytes of JSON, `{"program": <source>, "options": {...}}`.
+    pub fn decode(gif: &[u8]) -> Result<Self, String> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(gif).map_err(|why| format!("Couldn't read the cartridge: {}", why))?;
+
+        let mut payload = Vec::new();
+        let mut bits = 0;
+        let mut byte = 0u8;
+        while let Some(frame) = decoder.read_next_frame().map_err(|why| format!("Couldn't read the cartridge: {}", why))? {
+            for &index in frame.buffer.iter() {
+                byte = (byte << 2) | (index & 0b11);
+                bits += 2;
+                if bits == 8 {
+                    payload.push(byte);
+                    bits = 0;
+                }
+            }
+        }
+
+        if payload.len() < 4 {
+            return Err(String::from("The cartridge holds no program"));
+        }
+        let length = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+        let json = payload.get(4..4 + length).ok_or("The cartridge is truncated")?;
+        let json: Value = serde_json::from_slice(json).map_err(|why| format!("Couldn't parse the cartridge: {}", why))?;
+
+        let program = json["program"].as_str().ok_or("The cartridge holds no program")?;
+        Ok(Cartridge {
+            program: String::from(program),
+            metadata: metadata(&json["options"]),
+        })
+    }
+}
+
+// Octo's options, quirks named after the deviation from the COSMAC VIP like
+// the database does
+fn metadata(options: &Value) -> Metadata {
+    let flag = |name: &str| options[name].as_bool().unwrap_or(false);
+    let quirks = Quirks {
+        vf_reset: flag("logicQuirks"),
+        memory_increment: !flag("loadStoreQuirks"),
+        shift_vy: !flag("shiftQuirks"),
+        clip_sprites: flag("clipQuirks"),
+        jump_vx: flag("jumpQuirks"),
+        display_wait: flag("vBlankQuirks"),
+        ..Quirks::default()
+    };
+    let color = |name: &str| options[name].as_str().and_then(Palette::parse_color);
+    let palette = match (color("backgroundColor"), color("fillColor")) {
+        (Some(background), Some(foreground)) => Some(Palette { background, foreground }),
+        _ => None,
+    };
+
+    Metadata {
+        quirks: if options.is_object() { Some(quirks) } else { None },
+        tickrate: options["tickrate"].as_u64().map(|t| t as usize),
+        palette,
+        ..Metadata::default()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // A 64x32 cartridge with a 4 color palette, the way Octo spreads the
+    // payload over the color indices
+    pub(crate) fn cartridge(json: &str) -> Vec<u8> {
+        let mut payload = (json.len() as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(json.as_bytes());
+        let mut indices: Vec<u8> = payload.iter()
+            .flat_map(|&b| [b >> 6, (b >> 4) & 3, (b >> 2) & 3, b & 3])
+            .collect();
+        assert!(indices.len() <= 64 * 32);
+        indices.resize(64 * 32, 0);
+
+        let mut gif = Vec::new();
+        {
+            let palette = [0, 0, 0, 85, 85, 85, 170, 170, 170, 255, 255, 255];
+            let mut encoder = gif::Encoder::new(&mut gif, 64, 32, &palette).unwrap();
+            let frame = gif::Frame::from_indexed_pixels(64, 32, indices, None);
+            encoder.write_frame(&frame).unwrap();
+        }
+        gif
+    }
+
+    #[test]
+    fn the_program_and_options_are_extracted() {
+        let json = r##"{"program": ": main\n  loop again", "options": {
+            "tickrate": 20, "fillColor": "#FFCC00", "backgroundColor": "#996600",
+            "shiftQuirks": true, "loadStoreQuirks": true, "clipQuirks": true,
+            "jumpQuirks": true, "vBlankQuirks": false, "logicQuirks": false
+        }}"##;
+        let gif = cartridge(json);
+        assert!(is_cartridge(&gif));
+
+        let cartridge = Cartridge::decode(&gif).unwrap();
+        assert_eq!(cartridge.program, ": main\n  loop again");
+        assert_eq!(cartridge.metadata.tickrate, Some(20));
+        assert_eq!(cartridge.metadata.palette, Palette::by_name("octo"));
+        assert_eq!(cartridge.metadata.quirks, Quirks::by_name("schip"));
+    }
+
+    #[test]
+    fn images_without_a_payload_are_rejected() {
+        assert!(Cartridge::decode(&cartridge("{}")).is_err());
+        assert!(Cartridge::decode(b"GIF89a").is_err());
+    }
+}
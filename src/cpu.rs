@@ -1,7 +1,10 @@
 use crate::instruction::*;
+use crate::quirks::Quirks;
+use crate::stack::{Frame, Stack};
 
 use std::{thread, time};
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 pub static FONT_SET: [u8; 80] = [
   0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -22,32 +25,94 @@ pub static FONT_SET: [u8; 80] = [
   0xF0, 0x80, 0xF0, 0x80, 0x80  // F
 ];
 
+// Addresses wrap around the 4 KiB of memory
+const ADDRESS_MASK: usize = 0xFFF;
+const PROGRAM_START: usize = 0x200;
+/// Largest ROM that fits in memory after the interpreter area
+pub const MAX_ROM_SIZE: usize = 4096 - PROGRAM_START;
+
+pub const SCREEN_WIDTH: usize = 64;
+pub const SCREEN_HEIGHT: usize = 32;
+// Instructions run between two 60 Hz timer ticks, roughly 500 per second
+pub const INSTRUCTIONS_PER_FRAME: usize = 8;
+
 pub trait Chip8Machine {
     fn new() -> Self;
 
+    // Hard resets the machine with `rom` as its program
     fn load_rom(&mut self, rom: &[u8]) -> Result<(), &'static str>;
 
-    fn run(&mut self, key_map: [bool;16]) -> u16;
+    // Restarts the loaded ROM: reloads it and clears registers, stack,
+    // screen, timers and keys. The rest of memory is left as it was
+    fn reset(&mut self);
+
+    // Like reset, but also clears all memory and reseeds the RNG
+    fn hard_reset(&mut self);
+
+    // Runs one instruction and returns it, or the error that halted the machine
+    fn run(&mut self, key_map: [bool;16]) -> Result<u16, &'static str>;
+
+    // Called once per 60 Hz frame (vblank), also counts frames
+    fn tick_timers(&mut self);
 }
 
 trait InstructionMachine {
     fn fetch_instruction(&self) -> Chip8Instruction;
 
+    fn fetch_decoded(&mut self) -> (u16, Opcode);
+
+    fn write_memory(&mut self, address: usize, value: u8);
+
     fn increment_pc(&mut self);
+
+    fn mark_dirty(&mut self, rect: DirtyRect);
+}
+
+/// Bounding box of the screen pixels changed since the frontend last drew.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
 }
 
+impl DirtyRect {
+    pub fn full() -> Self {
+        DirtyRect { x: 0, y: 0, width: SCREEN_WIDTH, height: SCREEN_HEIGHT }
+    }
+
+    pub fn pixel(x: usize, y: usize) -> Self {
+        DirtyRect { x, y, width: 1, height: 1 }
+    }
+
+    pub fn union(&self, other: &DirtyRect) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        DirtyRect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Machine {
     memory: [u8;4096],
     pub registers: [u8;16],
     pub i: u16,
     pub delay_timer: u8,
     pub sound_timer: u8,
+    // Number of 60 Hz frames emulated so far
+    pub frames: u64,
     pub pc: usize,
-    pub sp: i16,
-    pub stack: Vec<u16>,
+    pub stack: Stack,
 
     pub cls: bool,
-    pub draw: bool,
+    // Pixels changed since the frontend last took it
+    pub dirty: Option<DirtyRect>,
     pub screen: [[bool;32];64],
     pub key_pressed: bool,
     pub key: u8,
@@ -56,18 +121,81 @@ pub struct Machine {
     pub waiting_key: bool,
     buf_key_received: i8,
     key_lock: u8,
+
+    pub quirks: Quirks,
+    // Set by DXYN with the display wait quirk until the next frame
+    waiting_vblank: bool,
+    // Error raised by the instruction being executed
+    fault: Option<&'static str>,
+    // Program restored by resets
+    rom: Vec<u8>,
+    // Source of CXNN, seeded on first use and dropped by hard resets
+    rng: Option<StdRng>,
+    // Instruction starting at each address, decoded the first time it ran.
+    // Allocated on the first fetch and dropped by resets
+    decoded: Vec<Option<(u16, Opcode)>>,
 }
 
 impl InstructionMachine for Machine {
     fn increment_pc(&mut self) {
-        self.pc += 2;
+        self.pc = (self.pc + 2) & ADDRESS_MASK;
     }
 
     fn fetch_instruction(&self) -> Chip8Instruction {
         let left: u8 = self.memory[self.pc];
-        let right: u8 = self.memory[self.pc+1];
+        let right: u8 = self.memory[(self.pc + 1) & ADDRESS_MASK];
         Chip8Instruction::new(left, right)
     }
+
+    fn fetch_decoded(&mut self) -> (u16, Opcode) {
+        if self.decoded.is_empty() {
+            self.decoded = vec![None; self.memory.len()];
+        }
+        if let Some(decoded) = self.decoded[self.pc] {
+            return decoded;
+        }
+        let instruction = self.fetch_instruction();
+        let decoded = (instruction.raw, instruction.decode());
+        self.decoded[self.pc] = Some(decoded);
+        decoded
+    }
+
+    // Every write goes through here so the instructions overlapping the
+    // byte are decoded again, keeping self-modifying programs working
+    fn write_memory(&mut self, address: usize, value: u8) {
+        let address = address & ADDRESS_MASK;
+        self.memory[address] = value;
+        if !self.decoded.is_empty() {
+            self.decoded[address] = None;
+            self.decoded[address.wrapping_sub(1) & ADDRESS_MASK] = None;
+        }
+    }
+
+    fn mark_dirty(&mut self, rect: DirtyRect) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(&rect),
+            None => rect,
+        });
+    }
+}
+
+impl Machine {
+    /// The 4 KiB of memory, for debuggers and other views of the machine.
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Writes a byte of memory from outside the program, e.g. a debugger,
+    /// making sure instructions overlapping it are decoded again.
+    pub fn poke(&mut self, address: usize, value: u8) {
+        self.write_memory(address, value);
+    }
+
+    /// Whether a draw made the machine wait for the next timer tick (see
+    /// `Quirks::display_wait`), so that running stalls until then.
+    pub fn is_waiting_vblank(&self) -> bool {
+        self.waiting_vblank
+    }
 }
 
 impl Chip8Machine for Machine {
@@ -79,11 +207,11 @@ impl Chip8Machine for Machine {
             i: 0,
             delay_timer: 0,
             sound_timer: 0,
+            frames: 0,
             pc: 0,
-            sp: -1,
-            stack: vec!(0;16),
+            stack: Stack::new(),
             cls: false,
-            draw: false,
+            dirty: None,
             screen: [[false;32];64],
             key_pressed: false,
             key: 0,
@@ -91,6 +219,12 @@ impl Chip8Machine for Machine {
             buf_key_received: -1,
             key_lock: 0,
             key_map: [false;16],
+            quirks: Quirks::default(),
+            waiting_vblank: false,
+            fault: None,
+            rom: Vec::new(),
+            rng: None,
+            decoded: Vec::new(),
         };
         // Copy fontset
         for i in 0..80 {
@@ -101,19 +235,49 @@ impl Chip8Machine for Machine {
 
 
     fn load_rom(&mut self, rom: &[u8]) -> Result<(), &'static str> {
-        let mem_prefix = 0x200;
-        let mut i = 0;
-        loop {
-            if rom.len() <= i { break; }
-            let instruction = rom[i];
-            self.memory[mem_prefix + i] = instruction;
-            i += 1;
-        }
-        self.pc = 0x200;
+        if rom.len() > MAX_ROM_SIZE {
+            return Err("ROM does not fit in memory");
+        }
+        self.rom = rom.to_vec();
+        self.hard_reset();
         Ok(())
     }
 
-    fn run(&mut self, key_map: [bool;16]) -> u16 {
+    fn reset(&mut self) {
+        self.registers = [0; 16];
+        self.i = 0;
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.frames = 0;
+        self.pc = PROGRAM_START;
+        self.stack.clear();
+        self.cls = false;
+        if self.screen.iter().flatten().any(|&pixel| pixel) {
+            self.screen = [[false; 32]; 64];
+            self.mark_dirty(DirtyRect::full());
+        }
+        self.key_pressed = false;
+        self.key = 0;
+        self.key_map = [false; 16];
+        self.waiting_key = false;
+        self.buf_key_received = -1;
+        self.key_lock = 0;
+        self.waiting_vblank = false;
+        self.fault = None;
+
+        let end = PROGRAM_START + self.rom.len();
+        self.memory[PROGRAM_START..end].copy_from_slice(&self.rom);
+        self.decoded.clear();
+    }
+
+    fn hard_reset(&mut self) {
+        self.memory = [0; 4096];
+        self.memory[..FONT_SET.len()].copy_from_slice(&FONT_SET);
+        self.rng = None;
+        self.reset();
+    }
+
+    fn run(&mut self, key_map: [bool;16]) -> Result<u16, &'static str> {
         // if self.key_lock > 0 {
         //     self.key_lock -= 1;
         // }
@@ -132,21 +296,16 @@ impl Chip8Machine for Machine {
         // }
 
         self.key_map = key_map;
-        if self.delay_timer > 0 {
-            self.delay_timer -= 1;
-            println!("Delay Timer: {}", self.delay_timer);
-        }
-        
-        if self.sound_timer > 0 {
-            self.sound_timer -= 1;
-            println!("Sound Timer: {}", self.sound_timer);
+        if self.waiting_vblank {
+            return Ok(0x0000);
         }
 
-        let instruction = self.fetch_instruction();
-        let ret: u16 = instruction.raw.clone();
-        println!("Executing OpCode => {}", format!("{:#x}", instruction.raw));
-        self.run_opcode(instruction);
-        ret
+        let (ret, opcode) = self.fetch_decoded();
+        self.execute(opcode);
+        match self.fault.take() {
+            Some(fault) => Err(fault),
+            None => Ok(ret),
+        }
         
         // if self.waiting_key && key_map == -1 {
         //     return 0;
@@ -154,11 +313,23 @@ impl Chip8Machine for Machine {
             
         // }
     }
+
+    fn tick_timers(&mut self) {
+        self.frames += 1;
+        self.waiting_vblank = false;
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
+        }
+        
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+        }
+    }
 }
 
 pub trait OpCodes {
 
-    fn run_opcode(&mut self, instruction: Chip8Instruction);
+    fn execute(&mut self, opcode: Opcode);
 
     // 0x00E0
     fn clear_screen(&mut self);
@@ -251,53 +422,43 @@ pub trait OpCodes {
 
 impl OpCodes for Machine {
 
-    fn run_opcode(&mut self, instruction: Chip8Instruction) {
-        match instruction {
-            _ if instruction.raw == 0x00E0 => self.clear_screen(),
-            _ if instruction.raw == 0x00EE => self.ret(),
-            i if instruction.match_masked(0xF000, 0x1000) => self.jump(i.raw & 0x0FFF),
-            i if i.match_masked(0xF000, 0xB000) => self.jump_plus(i.raw & 0x0FFF),
-            i if i.match_masked(0xF000, 0xC000) => self.random(i.parts[1], (i.raw & 0x00FF) as u8),
-            i if i.match_masked(0xF000, 0x6000) => self.set_register(i.parts[1], (i.raw & 0x00FF) as u8),
-            i if i.match_masked(0xF000, 0x7000) => self.add_value(i.parts[1], (i.raw & 0x00FF) as u8),
-            i if i.match_masked(0xF000, 0xA000) => self.set_index(i.raw & 0x0FFF),
-            i if i.match_masked(0xF000, 0xD000) => self.display(i.parts[1], i.parts[2], i.parts[3]),
-            i if i.match_masked(0xF0FF, 0xE09E) => self.if_key(i.parts[1]),
-            i if i.match_masked(0xF0FF, 0xE0A1) => self.if_not_key(i.parts[1]),
-            i if i.match_masked(0xF000, 0x3000) => self.cond_equals(i.parts[1], (i.raw & 0x00FF) as u8),
-            i if i.match_masked(0xF000, 0x4000) => self.cond_not_equals(i.parts[1], (i.raw & 0x00FF) as u8),
-            i if i.match_masked(0xF00F, 0x5000) => self.cond_reg_equals(i.parts[1], i.parts[2]),
-            i if i.match_masked(0xF000, 0x8000) => {
-                match (i.raw & 0x000F) as u8 {
-                    0x0 => self.set_reg(i.parts[1], i.parts[2]),
-                    0x1 => self.bitwise_or(i.parts[1], i.parts[2]),
-                    0x2 => self.bitwise_and(i.parts[1], i.parts[2]),
-                    0x3 => self.bitwise_xor(i.parts[1], i.parts[2]),
-                    0x4 => self.add_reg(i.parts[1], i.parts[2]),
-                    0x5 => self.sub_reg(i.parts[1], i.parts[2]),
-                    0x6 => self.shr(i.parts[1], i.parts[2]),
-                    0x7 => self.subn(i.parts[1], i.parts[2]),
-                    0xE => self.shl(i.parts[1], i.parts[2]),
-                    _ => self.noop(),
-                }
-            },
-            i if i.match_masked(0xF000, 0xF000) => {
-                match (i.raw & 0x00FF) as u8 {
-                    0x07 => self.get_delay(i.parts[1]),
-                    0x0A => self.get_key(i.parts[1]),
-                    0x15 => self.delay_timer(i.parts[1]),
-                    0x18 => self.sound_timer(i.parts[1]),
-                    0x1E => self.fx1e(i.parts[1]),
-                    0x29 => self.fontset(i.parts[1]),
-                    0x55 => self.reg_dump(i.parts[1]),
-                    0x65 => self.reg_load(i.parts[1]),
-                    0x33 => self.bcd(i.parts[1]),
-                    _ => self.noop(),
-                }
-            }
-            i if i.match_masked(0xF00F, 0x9000) => self.cond_reg_not_equals(i.parts[1], i.parts[2]),
-            i if i.match_masked(0xF000, 0x2000) => self.call(i.raw & 0x0FFF),
-            _ => self.noop()
+    fn execute(&mut self, opcode: Opcode) {
+        match opcode {
+            Opcode::ClearScreen => self.clear_screen(),
+            Opcode::Return => self.ret(),
+            Opcode::Jump(nnn) => self.jump(nnn),
+            Opcode::Call(nnn) => self.call(nnn),
+            Opcode::SkipEqual(x, nn) => self.cond_equals(x, nn),
+            Opcode::SkipNotEqual(x, nn) => self.cond_not_equals(x, nn),
+            Opcode::SkipRegEqual(x, y) => self.cond_reg_equals(x, y),
+            Opcode::SetRegister(x, nn) => self.set_register(x, nn),
+            Opcode::AddValue(x, nn) => self.add_value(x, nn),
+            Opcode::SetReg(x, y) => self.set_reg(x, y),
+            Opcode::Or(x, y) => self.bitwise_or(x, y),
+            Opcode::And(x, y) => self.bitwise_and(x, y),
+            Opcode::Xor(x, y) => self.bitwise_xor(x, y),
+            Opcode::AddReg(x, y) => self.add_reg(x, y),
+            Opcode::SubReg(x, y) => self.sub_reg(x, y),
+            Opcode::ShiftRight(x, y) => self.shr(x, y),
+            Opcode::SubN(x, y) => self.subn(x, y),
+            Opcode::ShiftLeft(x, y) => self.shl(x, y),
+            Opcode::SkipRegNotEqual(x, y) => self.cond_reg_not_equals(x, y),
+            Opcode::SetIndex(nnn) => self.set_index(nnn),
+            Opcode::JumpPlus(nnn) => self.jump_plus(nnn),
+            Opcode::Random(x, nn) => self.random(x, nn),
+            Opcode::Display(x, y, n) => self.display(x, y, n),
+            Opcode::SkipKey(x) => self.if_key(x),
+            Opcode::SkipNotKey(x) => self.if_not_key(x),
+            Opcode::GetDelay(x) => self.get_delay(x),
+            Opcode::GetKey(x) => self.get_key(x),
+            Opcode::SetDelay(x) => self.delay_timer(x),
+            Opcode::SetSound(x) => self.sound_timer(x),
+            Opcode::AddIndex(x) => self.fx1e(x),
+            Opcode::Font(x) => self.fontset(x),
+            Opcode::Bcd(x) => self.bcd(x),
+            Opcode::RegDump(x) => self.reg_dump(x),
+            Opcode::RegLoad(x) => self.reg_load(x),
+            Opcode::Unknown => self.noop(),
         }
     }
 
@@ -307,6 +468,7 @@ impl OpCodes for Machine {
                 self.screen[x][y] = false;
             }
         }
+        self.mark_dirty(DirtyRect::full());
         self.increment_pc();
     }
 
@@ -315,18 +477,21 @@ impl OpCodes for Machine {
     }
 
     fn call(&mut self, target: u16) {
-        self.sp += 1;
-        self.stack[self.sp as usize] = self.pc as u16;
-        self.pc = target as usize;
+        let frame = Frame { caller: self.pc as u16, callee: target };
+        match self.stack.push(frame, self.quirks.stack_depth) {
+            Ok(()) => self.pc = target as usize,
+            Err(fault) => self.fault = Some(fault),
+        }
     }
 
     fn ret(&mut self) {
-        if self.sp < 0 {
-            panic!("Return when stack pointer not yet set")
+        match self.stack.pop() {
+            Ok(frame) => {
+                self.pc = frame.caller as usize;
+                self.increment_pc();
+            }
+            Err(fault) => self.fault = Some(fault),
         }
-        self.pc = self.stack[self.sp as usize] as usize;
-        self.sp -= 1;
-        self.increment_pc();
     }
 
     fn set_register(&mut self, x: u8, nn: u8) {
@@ -335,11 +500,12 @@ impl OpCodes for Machine {
     }
 
     fn jump_plus(&mut self, index: u16) {
-        self.pc = (index + self.registers[0x0] as u16) as usize;
+        let x = if self.quirks.jump_vx { (index >> 8) as usize & 0xF } else { 0x0 };
+        self.pc = (index as usize + self.registers[x] as usize) & ADDRESS_MASK;
     }
 
     fn random(&mut self, x: u8, nn: u8) {
-        let mut rng = rand::thread_rng();
+        let rng = self.rng.get_or_insert_with(StdRng::from_entropy);
         let random_number: u8 = rng.gen();
         self.registers[x as usize] = random_number & nn;
         self.increment_pc();
@@ -391,59 +557,64 @@ impl OpCodes for Machine {
 
     fn bitwise_or(&mut self, x: u8, y: u8) {
         self.registers[x as usize] = self.registers[x as usize] | self.registers[y as usize];
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
         self.increment_pc();
     }
 
     fn bitwise_and(&mut self, x: u8, y: u8) {
         self.registers[x as usize] = self.registers[x as usize] & self.registers[y as usize];
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
         self.increment_pc();
     }
 
     fn bitwise_xor(&mut self, x: u8, y: u8) {
         self.registers[x as usize] = self.registers[x as usize] ^ self.registers[y as usize];
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
         self.increment_pc();
     }
 
+    // The arithmetic group writes the result before VF, so VF holds the
+    // flag even when it is also the target register (8FY4 and alike)
     fn add_reg(&mut self, x: u8, y: u8) {
-        let result: u16 = (self.registers[x as usize] as u16) + (self.registers[y as usize] as u16);
-        if result > 0xFF {
-            self.registers[0xF] = 0x1;
-        } else {
-            self.registers[0xF] = 0x0;
-        }
-        self.registers[x as usize] = result as u8;
+        let (result, carry) = self.registers[x as usize].overflowing_add(self.registers[y as usize]);
+        self.registers[x as usize] = result;
+        self.registers[0xF] = carry as u8;
         self.increment_pc();
     }
 
     fn sub_reg(&mut self, x: u8, y: u8) {
-        if self.registers[x as usize] > self.registers[y as usize] {
-            self.registers[0xF] = 0x1;
-        } else {
-            self.registers[0xF] = 0x0;
-        }
-        self.registers[x as usize] = i16::abs(self.registers[x as usize] as i16 - self.registers[y as usize] as i16) as u8;
+        let (result, borrow) = self.registers[x as usize].overflowing_sub(self.registers[y as usize]);
+        self.registers[x as usize] = result;
+        self.registers[0xF] = !borrow as u8;
         self.increment_pc();
     }
 
-    fn shr(&mut self, x: u8, _: u8) {
-        self.registers[0xF] = self.registers[x as usize] & 0x01;
-        self.registers[x as usize] = self.registers[x as usize] >> 1;
+    fn shr(&mut self, x: u8, y: u8) {
+        let source = if self.quirks.shift_vy { self.registers[y as usize] } else { self.registers[x as usize] };
+        let shifted_out = source & 0x01;
+        self.registers[x as usize] = source >> 1;
+        self.registers[0xF] = shifted_out;
         self.increment_pc();
     }
 
     fn subn(&mut self, x: u8, y: u8) {
-        if self.registers[y as usize] > self.registers[x as usize] {
-            self.registers[0xF] = 0x1;
-        } else {
-            self.registers[0xF] = 0x0;
-        }
-        self.registers[x as usize] = self.registers[y as usize].wrapping_sub(self.registers[x as usize]);
+        let (result, borrow) = self.registers[y as usize].overflowing_sub(self.registers[x as usize]);
+        self.registers[x as usize] = result;
+        self.registers[0xF] = !borrow as u8;
         self.increment_pc();
     }
 
-    fn shl(&mut self, x: u8, _: u8) {
-        self.registers[0xF] = (self.registers[x as usize] >> 7) & 0x1;
-        self.registers[x as usize] = self.registers[x as usize] << 1;
+    fn shl(&mut self, x: u8, y: u8) {
+        let source = if self.quirks.shift_vy { self.registers[y as usize] } else { self.registers[x as usize] };
+        let shifted_out = (source >> 7) & 0x1;
+        self.registers[x as usize] = source << 1;
+        self.registers[0xF] = shifted_out;
         self.increment_pc();
     }
 
@@ -456,7 +627,7 @@ impl OpCodes for Machine {
         let mut is_key_pressed = false;
         let mut key_pressed = 0x0;
         
-        for i in 0x0..0xf {
+        for i in 0x0..16 {
             if self.key_map[i] {
                 is_key_pressed = true;
                 key_pressed = i;
@@ -472,7 +643,7 @@ impl OpCodes for Machine {
 
     // 0xEX9E
     fn if_key (&mut self, x: u8) {
-        if self.key_map[self.registers[x as usize] as usize] {
+        if self.key_map[(self.registers[x as usize] & 0xF) as usize] {
             self.increment_pc();
         }
         self.increment_pc();
@@ -480,7 +651,7 @@ impl OpCodes for Machine {
 
     // 0xEXA1
     fn if_not_key (&mut self, x: u8) {
-        if !self.key_map[self.registers[x as usize] as usize] {
+        if !self.key_map[(self.registers[x as usize] & 0xF) as usize] {
             self.increment_pc();
         }
         self.increment_pc();
@@ -491,41 +662,47 @@ impl OpCodes for Machine {
         self.increment_pc();
     }
 
-    fn sound_timer(&mut self, _: u8) {
-        // ignore for now
+    fn sound_timer(&mut self, x: u8) {
+        self.sound_timer = self.registers[x as usize];
         self.increment_pc();
     }
 
     fn fx1e(&mut self, x: u8) {
-        self.i += self.registers[x as usize] as u16;
+        self.i = self.i.wrapping_add(self.registers[x as usize] as u16);
         self.increment_pc();
     }
 
     fn fontset(&mut self, x: u8) {
         let font_index = self.registers[x as usize];
-        self.i = (font_index * 5) as u16;
+        self.i = (font_index & 0xF) as u16 * 5;
         self.increment_pc();
     }
 
     fn reg_dump(&mut self, x: u8) {
         for i in 0..((x+1) as usize) {
-            self.memory[(self.i + i as u16) as usize] = self.registers[i];
+            self.write_memory(self.i as usize + i, self.registers[i]);
+        }
+        if self.quirks.memory_increment {
+            self.i = self.i.wrapping_add(x as u16 + 1);
         }
         self.increment_pc();
     }
 
     fn reg_load(&mut self, x: u8) {
         for i in 0..((x+1) as usize) {
-            self.registers[i] = self.memory[(self.i + i as u16) as usize];
+            self.registers[i] = self.memory[(self.i as usize + i) & ADDRESS_MASK];
+        }
+        if self.quirks.memory_increment {
+            self.i = self.i.wrapping_add(x as u16 + 1);
         }
         self.increment_pc();
     }
 
     fn bcd(&mut self, x: u8) {
         let i = self.registers[x as usize];
-        self.memory[self.i as usize] = i / 100;
-        self.memory[(self.i+1) as usize] = (i / 10) % 10;
-        self.memory[(self.i+2) as usize] = i % 10;
+        self.write_memory(self.i as usize, i / 100);
+        self.write_memory(self.i as usize + 1, (i / 10) % 10);
+        self.write_memory(self.i as usize + 2, i % 10);
         self.increment_pc();
     }
 
@@ -533,12 +710,21 @@ impl OpCodes for Machine {
         let x_start = self.registers[x as usize] % 64;
         let y_start = self.registers[y as usize] % 32;
 
-        let sprite = &self.memory[self.i as usize .. (self.i + (height as u16)) as usize];
+        let mut sprite = [0u8; 16];
+        for (row, byte) in sprite.iter_mut().enumerate().take(height as usize) {
+            *byte = self.memory[(self.i as usize + row) & ADDRESS_MASK];
+        }
+        let sprite = &sprite[..height as usize];
 
         let mut collision = false;
+        let mut changed: Option<DirtyRect> = None;
         for y in 0..sprite.len() {
             let sprite_byte = sprite[y];
             for x in 0..8 {
+                let clipped = x_start as usize + x >= 64 || y_start as usize + y >= 32;
+                if clipped && self.quirks.clip_sprites {
+                    continue;
+                }
                 let x_index = ((x_start + (x as u8)) % 64) as usize;
                 let y_index = ((y_start + (y as u8)) % 32) as usize;
                 if ((sprite_byte >> (7 - x)) & 0x01) == 0x01 {
@@ -546,11 +732,16 @@ impl OpCodes for Machine {
                         collision = true;
                     }
                     self.screen[x_index][y_index] = !self.screen[x_index][y_index];
+                    let pixel = DirtyRect::pixel(x_index, y_index);
+                    changed = Some(changed.map_or(pixel, |c| c.union(&pixel)));
                 }
             } 
         }
+        if let Some(changed) = changed {
+            self.mark_dirty(changed);
+        }
         self.registers[0xF] = if collision { 0x1 } else { 0x0 };
-        self.draw = true;
+        self.waiting_vblank = self.quirks.display_wait;
         self.increment_pc();
     }
 
@@ -559,3 +750,473 @@ impl OpCodes for Machine {
         // println!("[NoOp] Executing OpCode => {}", format!("{:#x}", self.fetch_instruction().raw));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(program: &[u8]) -> Machine {
+        let mut m = Machine::new();
+        m.load_rom(program).unwrap();
+        m
+    }
+
+    fn step(m: &mut Machine, count: usize) {
+        for _ in 0..count {
+            m.run([false; 16]).unwrap();
+        }
+    }
+
+    #[test]
+    fn load_rom_starts_at_0x200_after_the_font() {
+        let m = machine(&[0x12, 0x34]);
+        assert_eq!(m.pc, 0x200);
+        assert_eq!(m.memory[0x200..0x202], [0x12, 0x34]);
+        assert_eq!(m.memory[..80], FONT_SET[..]);
+    }
+
+    #[test]
+    fn clear_screen() {
+        let mut m = machine(&[0x00, 0xE0]);
+        m.screen[3][4] = true;
+        step(&mut m, 1);
+        assert!(m.screen.iter().flatten().all(|&p| !p));
+        assert_eq!(m.dirty, Some(DirtyRect::full()));
+        assert_eq!(m.pc, 0x202);
+    }
+
+    #[test]
+    fn call_and_ret() {
+        // 0x200: call 0x206, 0x202: jump to self, 0x206: ret
+        let mut m = machine(&[0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x00, 0xEE]);
+        step(&mut m, 1);
+        assert_eq!(m.pc, 0x206);
+        assert_eq!(m.stack.frames(), [Frame { caller: 0x200, callee: 0x206 }]);
+        step(&mut m, 1);
+        assert_eq!(m.pc, 0x202);
+        assert!(m.stack.is_empty());
+    }
+
+    #[test]
+    fn stack_overflow_and_underflow_are_errors() {
+        // 0x200: call 0x200
+        let mut m = machine(&[0x22, 0x00]);
+        step(&mut m, 16);
+        assert_eq!(m.run([false; 16]), Err("Stack overflow"));
+        assert_eq!(m.stack.len(), 16);
+
+        // The COSMAC VIP only had room for 12 return addresses
+        let mut m = machine(&[0x22, 0x00]);
+        m.quirks = Quirks::by_name("chip8").unwrap();
+        step(&mut m, 12);
+        assert_eq!(m.run([false; 16]), Err("Stack overflow"));
+        assert_eq!(m.pc, 0x200);
+
+        let mut m = machine(&[0x00, 0xEE]);
+        assert_eq!(m.run([false; 16]), Err("Stack underflow"));
+    }
+
+    #[test]
+    fn addresses_wrap_around_memory() {
+        let mut m = machine(&[0xF0, 0x33, 0xD0, 0x12, 0xF1, 0x65]);
+        m.i = 0xFFF;
+        m.registers[0] = 123;
+        step(&mut m, 3);
+        assert_eq!((m.memory[0xFFF], m.memory[0x000], m.memory[0x001]), (1, 2, 3));
+        assert_eq!(m.registers[..2], [1, 2]);
+    }
+
+    #[test]
+    fn oversized_rom_is_rejected() {
+        let mut m = Machine::new();
+        assert!(m.load_rom(&[0; 4096 - 0x200]).is_ok());
+        assert!(m.load_rom(&[0; 4096 - 0x200 + 1]).is_err());
+    }
+
+    #[test]
+    fn reset_restarts_the_rom() {
+        // Overwrites its own first instruction, then draws and calls
+        let mut m = machine(&[0x60, 0x00, 0xA2, 0x00, 0xF0, 0x55, 0xA3, 0x00, 0xD0, 0x01, 0x22, 0x00]);
+        m.memory[0x300] = 0xAB;
+        step(&mut m, 6);
+        m.delay_timer = 10;
+        m.dirty = None;
+        assert_eq!(m.memory[0x200], 0x00);
+        assert_eq!(m.stack.len(), 1);
+        assert!(m.screen[0][0]);
+
+        m.reset();
+        assert_eq!(m.memory[0x200..0x202], [0x60, 0x00]);
+        assert_eq!(m.memory[0x300], 0xAB);
+        assert_eq!((m.pc, m.i, m.delay_timer), (0x200, 0, 0));
+        assert!(m.stack.is_empty());
+        assert!(m.screen.iter().flatten().all(|&p| !p));
+        assert_eq!(m.dirty, Some(DirtyRect::full()));
+
+        m.hard_reset();
+        assert_eq!(m.memory[0x300], 0x00);
+        assert_eq!(m.memory[..80], FONT_SET[..]);
+        assert_eq!(m.memory[0x200..0x202], [0x60, 0x00]);
+    }
+
+    #[test]
+    fn loading_a_rom_clears_the_previous_one() {
+        let mut m = machine(&[0x12, 0x34, 0x56, 0x78]);
+        m.registers[3] = 7;
+        m.load_rom(&[0xAB, 0xCD]).unwrap();
+        assert_eq!(m.memory[0x200..0x204], [0xAB, 0xCD, 0x00, 0x00]);
+        assert_eq!(m.registers[3], 0);
+    }
+
+    #[test]
+    fn self_modifying_code_is_decoded_again() {
+        let mut m = machine(&[
+            0x22, 0x0C, // call 0x20C
+            0x60, 0x62, // V0 = 0x62
+            0x61, 0x09, // V1 = 0x09
+            0xA2, 0x0C, // I = 0x20C
+            0xF1, 0x55, // overwrite 0x20C with V0, V1
+            0x22, 0x0C, // call 0x20C
+            0x62, 0x05, // V2 = 5, becomes V2 = 9
+            0x00, 0xEE, // ret
+        ]);
+        step(&mut m, 3);
+        assert_eq!(m.registers[2], 5);
+        step(&mut m, 6);
+        assert_eq!(m.registers[2], 9);
+    }
+
+    #[test]
+    fn jump_and_jump_plus() {
+        let mut m = machine(&[0x13, 0x00]);
+        step(&mut m, 1);
+        assert_eq!(m.pc, 0x300);
+
+        let mut m = machine(&[0xB3, 0x00]);
+        m.registers[0] = 0x10;
+        step(&mut m, 1);
+        assert_eq!(m.pc, 0x310);
+    }
+
+    #[test]
+    fn conditional_skips() {
+        let mut m = machine(&[0x30, 0x05, 0x00, 0x00, 0x40, 0x05, 0x50, 0x10, 0x00, 0x00, 0x90, 0x10]);
+        m.registers[0] = 0x05;
+        m.registers[1] = 0x05;
+        step(&mut m, 1); // 3XNN equal, skips
+        assert_eq!(m.pc, 0x204);
+        step(&mut m, 1); // 4XNN equal, no skip
+        assert_eq!(m.pc, 0x206);
+        step(&mut m, 1); // 5XY0 equal, skips
+        assert_eq!(m.pc, 0x20A);
+        step(&mut m, 1); // 9XY0 equal, no skip
+        assert_eq!(m.pc, 0x20C);
+    }
+
+    #[test]
+    fn set_and_add_value() {
+        let mut m = machine(&[0x6A, 0xFE, 0x7A, 0x03]);
+        step(&mut m, 1);
+        assert_eq!(m.registers[0xA], 0xFE);
+        step(&mut m, 1);
+        assert_eq!(m.registers[0xA], 0x01);
+        // 7XNN never touches the flag
+        assert_eq!(m.registers[0xF], 0x00);
+    }
+
+    #[test]
+    fn logic_operations() {
+        let mut m = machine(&[0x80, 0x10, 0x82, 0x11, 0x83, 0x12, 0x84, 0x13]);
+        m.registers = [0; 16];
+        m.registers[1] = 0b1100;
+        m.registers[2] = 0b1010;
+        m.registers[3] = 0b1010;
+        m.registers[4] = 0b1010;
+        step(&mut m, 4);
+        assert_eq!(m.registers[0], 0b1100);
+        assert_eq!(m.registers[2], 0b1110);
+        assert_eq!(m.registers[3], 0b1000);
+        assert_eq!(m.registers[4], 0b0110);
+    }
+
+    #[test]
+    fn add_reg_sets_carry() {
+        let mut m = machine(&[0x80, 0x14, 0x80, 0x14]);
+        m.registers[0] = 0xF0;
+        m.registers[1] = 0x20;
+        step(&mut m, 1);
+        assert_eq!(m.registers[0], 0x10);
+        assert_eq!(m.registers[0xF], 1);
+        step(&mut m, 1);
+        assert_eq!(m.registers[0], 0x30);
+        assert_eq!(m.registers[0xF], 0);
+    }
+
+    #[test]
+    fn sub_reg_sets_not_borrow() {
+        let mut m = machine(&[0x80, 0x15]);
+        m.registers[0] = 0x30;
+        m.registers[1] = 0x10;
+        step(&mut m, 1);
+        assert_eq!(m.registers[0], 0x20);
+        assert_eq!(m.registers[0xF], 1);
+    }
+
+    #[test]
+    fn sub_reg_wraps_on_borrow() {
+        let mut m = machine(&[0x80, 0x15, 0x80, 0x15]);
+        m.registers[0] = 0x10;
+        m.registers[1] = 0x30;
+        step(&mut m, 1);
+        assert_eq!(m.registers[0], 0xE0);
+        assert_eq!(m.registers[0xF], 0);
+        // Equal operands do not borrow
+        m.registers[1] = 0xE0;
+        step(&mut m, 1);
+        assert_eq!(m.registers[0], 0x00);
+        assert_eq!(m.registers[0xF], 1);
+    }
+
+    #[test]
+    fn arithmetic_on_vf_keeps_the_flag() {
+        // 8FE4, 8FE5, 8FE7, 8FE6 and 8FEE with VF as VX
+        let mut m = machine(&[0x8F, 0xE4, 0x8F, 0xE5, 0x8F, 0xE7, 0x8F, 0xE6, 0x8F, 0xEE]);
+        m.registers[0xF] = 0xFF;
+        m.registers[0xE] = 0x01;
+        step(&mut m, 1);
+        assert_eq!(m.registers[0xF], 1);
+        m.registers[0xF] = 0x00;
+        step(&mut m, 1);
+        assert_eq!(m.registers[0xF], 0);
+        m.registers[0xF] = 0x05;
+        step(&mut m, 1);
+        assert_eq!(m.registers[0xF], 0);
+        m.registers[0xF] = 0x03;
+        step(&mut m, 1);
+        assert_eq!(m.registers[0xF], 1);
+        m.registers[0xF] = 0x40;
+        step(&mut m, 1);
+        assert_eq!(m.registers[0xF], 0);
+    }
+
+    #[test]
+    fn subn_sets_not_borrow() {
+        let mut m = machine(&[0x80, 0x17, 0x80, 0x17]);
+        m.registers[0] = 0x10;
+        m.registers[1] = 0x30;
+        step(&mut m, 1);
+        assert_eq!(m.registers[0], 0x20);
+        assert_eq!(m.registers[0xF], 1);
+        m.registers[1] = 0x10;
+        step(&mut m, 1);
+        assert_eq!(m.registers[0], 0xF0);
+        assert_eq!(m.registers[0xF], 0);
+    }
+
+    #[test]
+    fn shifts_set_the_shifted_out_bit() {
+        let mut m = machine(&[0x80, 0x06, 0x80, 0x06, 0x81, 0x0E, 0x81, 0x0E]);
+        m.registers[0] = 0b0000_0101;
+        m.registers[1] = 0b0100_0000;
+        step(&mut m, 1);
+        assert_eq!((m.registers[0], m.registers[0xF]), (0b0000_0010, 1));
+        step(&mut m, 1);
+        assert_eq!((m.registers[0], m.registers[0xF]), (0b0000_0001, 0));
+        step(&mut m, 1);
+        assert_eq!((m.registers[1], m.registers[0xF]), (0b1000_0000, 0));
+        step(&mut m, 1);
+        assert_eq!((m.registers[1], m.registers[0xF]), (0b0000_0000, 1));
+    }
+
+    #[test]
+    fn shifts_read_vy_with_quirk() {
+        let mut m = machine(&[0x80, 0x16, 0x80, 0x1E]);
+        m.quirks.shift_vy = true;
+        m.registers[1] = 0b1000_0011;
+        step(&mut m, 1);
+        assert_eq!((m.registers[0], m.registers[0xF]), (0b0100_0001, 1));
+        step(&mut m, 1);
+        assert_eq!((m.registers[0], m.registers[0xF]), (0b0000_0110, 1));
+    }
+
+    #[test]
+    fn logic_resets_vf_with_quirk() {
+        let mut m = machine(&[0x80, 0x11, 0x80, 0x12, 0x80, 0x13]);
+        m.quirks.vf_reset = true;
+        for _ in 0..3 {
+            m.registers[0xF] = 0x7;
+            step(&mut m, 1);
+            assert_eq!(m.registers[0xF], 0);
+        }
+    }
+
+    #[test]
+    fn jump_plus_uses_vx_with_quirk() {
+        let mut m = machine(&[0xB3, 0x00]);
+        m.quirks.jump_vx = true;
+        m.registers[0] = 0x10;
+        m.registers[3] = 0x20;
+        step(&mut m, 1);
+        assert_eq!(m.pc, 0x320);
+    }
+
+    #[test]
+    fn set_index_and_fx1e() {
+        let mut m = machine(&[0xA1, 0x23, 0xF0, 0x1E]);
+        m.registers[0] = 0x10;
+        step(&mut m, 1);
+        assert_eq!(m.i, 0x123);
+        step(&mut m, 1);
+        assert_eq!(m.i, 0x133);
+    }
+
+    #[test]
+    fn random_is_masked() {
+        let mut m = machine(&[0xC0, 0x00, 0xC1, 0x0F]);
+        step(&mut m, 2);
+        assert_eq!(m.registers[0], 0);
+        assert!(m.registers[1] <= 0x0F);
+    }
+
+    #[test]
+    fn display_draws_and_detects_collisions() {
+        // Draw the font's 0 at (1, 2) twice
+        let mut m = machine(&[0xA0, 0x00, 0xD0, 0x15, 0xD0, 0x15]);
+        m.registers[0] = 1;
+        m.registers[1] = 2;
+        step(&mut m, 2);
+        // Top row of 0 is 0xF0
+        assert!(m.screen[1][2] && m.screen[4][2] && !m.screen[5][2]);
+        assert_eq!(m.registers[0xF], 0);
+        assert_eq!(m.dirty, Some(DirtyRect { x: 1, y: 2, width: 4, height: 5 }));
+
+        step(&mut m, 1);
+        assert!(m.screen.iter().flatten().all(|&p| !p));
+        assert_eq!(m.registers[0xF], 1);
+    }
+
+    #[test]
+    fn display_clips_with_quirk() {
+        let mut m = machine(&[0xA0, 0x00, 0xD0, 0x15]);
+        m.quirks.clip_sprites = true;
+        m.registers[0] = 62;
+        m.registers[1] = 30;
+        step(&mut m, 2);
+        assert!(m.screen[62][30] && m.screen[63][30]);
+        assert!(!m.screen[0][30] && !m.screen[1][30]);
+        assert!(!m.screen[62][0]);
+    }
+
+    #[test]
+    fn display_waits_for_vblank_with_quirk() {
+        let mut m = machine(&[0xD0, 0x15, 0x60, 0x01]);
+        m.quirks.display_wait = true;
+        step(&mut m, 3);
+        assert_eq!(m.pc, 0x202);
+        m.tick_timers();
+        step(&mut m, 1);
+        assert_eq!(m.pc, 0x204);
+    }
+
+    #[test]
+    fn display_wraps_around_the_edges() {
+        let mut m = machine(&[0xA0, 0x00, 0xD0, 0x15]);
+        m.registers[0] = 62;
+        m.registers[1] = 30;
+        step(&mut m, 2);
+        assert!(m.screen[62][30] && m.screen[63][30]);
+        assert!(m.screen[0][30] && m.screen[1][30]);
+        assert!(m.screen[62][0]);
+    }
+
+    #[test]
+    fn key_skips() {
+        let mut m = machine(&[0xE0, 0x9E, 0x00, 0x00, 0xE0, 0xA1]);
+        m.registers[0] = 0x5;
+        let mut keys = [false; 16];
+        keys[0x5] = true;
+        m.run(keys).unwrap();
+        assert_eq!(m.pc, 0x204);
+        m.run(keys).unwrap();
+        assert_eq!(m.pc, 0x206);
+    }
+
+    #[test]
+    fn get_key_waits_for_a_key() {
+        let mut m = machine(&[0xF3, 0x0A]);
+        step(&mut m, 3);
+        assert_eq!(m.pc, 0x200);
+        let mut keys = [false; 16];
+        keys[0x7] = true;
+        m.run(keys).unwrap();
+        assert_eq!(m.pc, 0x202);
+        assert_eq!(m.registers[3], 0x7);
+    }
+
+    #[test]
+    fn get_key_sees_key_f() {
+        let mut m = machine(&[0xF3, 0x0A]);
+        let mut keys = [false; 16];
+        keys[0xF] = true;
+        m.run(keys).unwrap();
+        assert_eq!(m.pc, 0x202);
+        assert_eq!(m.registers[3], 0xF);
+    }
+
+    #[test]
+    fn timers() {
+        let mut m = machine(&[0xF0, 0x15, 0xF0, 0x18, 0xF1, 0x07]);
+        m.registers[0] = 2;
+        step(&mut m, 2);
+        assert_eq!((m.delay_timer, m.sound_timer), (2, 2));
+        m.tick_timers();
+        step(&mut m, 1);
+        assert_eq!(m.registers[1], 1);
+        m.tick_timers();
+        m.tick_timers();
+        assert_eq!((m.delay_timer, m.sound_timer), (0, 0));
+        assert_eq!(m.frames, 3);
+    }
+
+    #[test]
+    fn fontset_points_to_the_digit() {
+        let mut m = machine(&[0xF0, 0x29]);
+        m.registers[0] = 0xA;
+        step(&mut m, 1);
+        assert_eq!(m.i, 50);
+    }
+
+    #[test]
+    fn bcd() {
+        let mut m = machine(&[0xF0, 0x33]);
+        m.registers[0] = 254;
+        m.i = 0x300;
+        step(&mut m, 1);
+        assert_eq!(m.memory[0x300..0x303], [2, 5, 4]);
+    }
+
+    #[test]
+    fn reg_dump_and_load() {
+        let mut m = machine(&[0xF2, 0x55, 0xF1, 0x65]);
+        m.registers[..3].copy_from_slice(&[7, 8, 9]);
+        m.i = 0x300;
+        step(&mut m, 1);
+        assert_eq!(m.memory[0x300..0x304], [7, 8, 9, 0]);
+        assert_eq!(m.i, 0x300);
+
+        m.registers[..3].copy_from_slice(&[0, 0, 0]);
+        step(&mut m, 1);
+        assert_eq!(m.registers[..3], [7, 8, 0]);
+    }
+
+    #[test]
+    fn reg_dump_and_load_increment_i_with_quirk() {
+        let mut m = machine(&[0xF2, 0x55, 0xF1, 0x65]);
+        m.quirks.memory_increment = true;
+        m.i = 0x300;
+        step(&mut m, 1);
+        assert_eq!(m.i, 0x303);
+        step(&mut m, 1);
+        assert_eq!(m.i, 0x305);
+    }
+}
@@ -0,0 +1,623 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::cpu::{self, Chip8Machine, Machine};
+use crate::database::Database;
+use crate::external;
+use crate::instruction::Opcode;
+use crate::quirks::Quirks;
+use crate::source_map::SourceMap;
+
+// The machine has a single thread of execution
+const THREAD_ID: u64 = 1;
+
+// Variable references of the two scopes
+const REGISTERS_REFERENCE: u64 = 1;
+const STACK_REFERENCE: u64 = 2;
+
+/// Reads a Debug Adapter Protocol message: a `Content-Length` header, a
+/// blank line and that many bytes of JSON. `None` when the input ended.
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let mut body = vec![0; length.unwrap_or(0)];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|why| io::Error::new(ErrorKind::InvalidData, why))
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Stopped,
+    Running,
+    // Runs until no more than this many calls are nested, to step over or
+    // out of a subroutine
+    StepOut(usize),
+}
+
+/// A debug session of a `Machine` driven by Debug Adapter Protocol
+/// requests. It doesn't do any I/O: the adapter passes it the requests,
+/// runs a frame of it every 60th of a second while it is running and sends
+/// the responses and events it queued.
+pub struct DebugSession {
+    machine: Machine,
+    tickrate: usize,
+    source_map: Option<SourceMap>,
+    instruction_breakpoints: BTreeSet<u16>,
+    source_breakpoints: HashMap<PathBuf, Vec<u16>>,
+    stop_on_entry: bool,
+    mode: Mode,
+    // Breakpoint the machine was resumed from, not hit again right away
+    resumed_at: Option<u16>,
+    seq: u64,
+    outgoing: Vec<Value>,
+    finished: bool,
+}
+
+impl Default for DebugSession {
+    fn default() -> Self {
+        DebugSession::new()
+    }
+}
+
+impl DebugSession {
+    pub fn new() -> Self {
+        DebugSession {
+            machine: Machine::new(),
+            tickrate: cpu::INSTRUCTIONS_PER_FRAME,
+            source_map: None,
+            instruction_breakpoints: BTreeSet::new(),
+            source_breakpoints: HashMap::new(),
+            stop_on_entry: false,
+            mode: Mode::Stopped,
+            resumed_at: None,
+            seq: 0,
+            outgoing: Vec::new(),
+            finished: false,
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// Whether the machine runs on its own, see `run_frame`.
+    pub fn is_running(&self) -> bool {
+        self.mode != Mode::Stopped
+    }
+
+    /// Whether the client disconnected.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Responses and events to send, in order.
+    pub fn take_messages(&mut self) -> Vec<Value> {
+        let mut messages = std::mem::take(&mut self.outgoing);
+        for message in messages.iter_mut() {
+            self.seq += 1;
+            message["seq"] = Value::from(self.seq);
+        }
+        messages
+    }
+
+    /// Answers a request, messages other than requests are ignored.
+    pub fn handle(&mut self, message: &Value) {
+        if message["type"] != "request" {
+            return;
+        }
+        let command = message["command"].as_str().unwrap_or_default();
+        let args = &message["arguments"];
+        let queued = self.outgoing.len();
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+                "supportsSteppingGranularity": false,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(args)),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.stop("entry", None);
+                } else {
+                    self.resume(Mode::Running);
+                }
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK_REFERENCE, "expensive": false },
+            ] })),
+            "variables" => Ok(self.variables(args["variablesReference"].as_u64().unwrap_or(0))),
+            "continue" => {
+                self.resume(Mode::Running);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                if self.is_call() {
+                    let depth = self.machine.stack.len();
+                    self.resume(Mode::StepOut(depth));
+                } else {
+                    self.step();
+                }
+                Ok(json!({}))
+            }
+            "stepIn" => {
+                self.step();
+                Ok(json!({}))
+            }
+            "stepOut" => {
+                match self.machine.stack.len() {
+                    0 => self.step(),
+                    depth => self.resume(Mode::StepOut(depth - 1)),
+                }
+                Ok(json!({}))
+            }
+            "pause" => {
+                self.stop("pause", None);
+                Ok(json!({}))
+            }
+            "readMemory" => self.read_memory(args),
+            "terminate" => {
+                self.mode = Mode::Stopped;
+                self.event("terminated", json!({}));
+                Ok(json!({}))
+            }
+            "disconnect" => {
+                self.mode = Mode::Stopped;
+                self.finished = true;
+                Ok(json!({}))
+            }
+            _ => Err(format!("Unsupported request {}", command)),
+        };
+
+        // The response goes before the events it caused
+        let events = self.outgoing.split_off(queued);
+        let mut response = json!({
+            "type": "response",
+            "request_seq": message["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(why) => response["message"] = Value::from(why),
+        }
+        self.outgoing.push(response);
+        if command == "initialize" {
+            self.event("initialized", json!({}));
+        }
+        self.outgoing.extend(events);
+    }
+
+    /// Runs a 60 Hz frame, stopping early on breakpoints and errors.
+    pub fn run_frame(&mut self) {
+        for _ in 0..self.tickrate {
+            if !self.is_running() {
+                return;
+            }
+            let pc = self.machine.pc as u16;
+            if self.resumed_at.take() != Some(pc) && self.is_breakpoint(pc) {
+                self.stop("breakpoint", None);
+                return;
+            }
+            if self.machine.is_waiting_vblank() {
+                break;
+            }
+            if let Err(why) = self.machine.run(self.machine.key_map) {
+                self.stop("exception", Some(why));
+                return;
+            }
+            if let Mode::StepOut(depth) = self.mode {
+                if self.machine.stack.len() <= depth {
+                    self.stop("step", None);
+                    return;
+                }
+            }
+        }
+        self.machine.tick_timers();
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"].as_str().ok_or("launch expects a program")?;
+        let rom = external::load_rom(program, &Database::bundled(), |_| None)?;
+        let metadata = rom.metadata.unwrap_or_default();
+
+        let quirks = match args["quirks"].as_str() {
+            Some(name) => Some(Quirks::by_name(name).ok_or(format!("Unknown quirk preset {}", name))?),
+            None => metadata.quirks,
+        };
+        self.machine.quirks = quirks.unwrap_or_default();
+        self.machine.load_rom(&rom.bytes)?;
+        self.tickrate = args["tickrate"].as_u64().map(|t| t as usize)
+            .or(metadata.tickrate)
+            .unwrap_or(cpu::INSTRUCTIONS_PER_FRAME);
+        self.source_map = match args["sourceMap"].as_str() {
+            Some(path) => Some(SourceMap::load(Path::new(path))?),
+            None => None,
+        };
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+
+        if !metadata.title.is_empty() {
+            self.event("output", json!({ "category": "console", "output": format!("{}\n", metadata.credits()) }));
+        }
+        Ok(json!({}))
+    }
+
+    // Breakpoints on source lines are moved to the next line with code, and
+    // left unverified without a source map
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let path = PathBuf::from(args["source"]["path"].as_str().unwrap_or_default());
+        let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+
+        let mut addresses = Vec::new();
+        let mut breakpoints = Vec::new();
+        for breakpoint in requested {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as u32;
+            let mapping = self.source_map.as_ref().and_then(|map| map.address_of(&path, line));
+            breakpoints.push(match mapping {
+                Some(mapping) => {
+                    addresses.push(mapping.address);
+                    json!({
+                        "verified": true,
+                        "line": mapping.line,
+                        "instructionReference": reference(mapping.address),
+                    })
+                }
+                None => json!({ "verified": false, "line": line, "message": "No code at this line" }),
+            });
+        }
+        self.source_breakpoints.insert(path, addresses);
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Value {
+        let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+        self.instruction_breakpoints.clear();
+
+        let mut breakpoints = Vec::new();
+        for breakpoint in requested {
+            let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+            let address = breakpoint["instructionReference"].as_str()
+                .and_then(parse_reference)
+                .and_then(|a| a.checked_add(offset))
+                .filter(|a| (0..4096).contains(a));
+            breakpoints.push(match address {
+                Some(address) => {
+                    self.instruction_breakpoints.insert(address as u16);
+                    json!({ "verified": true, "instructionReference": reference(address as u16) })
+                }
+                None => json!({ "verified": false, "message": "Not an address in memory" }),
+            });
+        }
+        json!({ "breakpoints": breakpoints })
+    }
+
+    // The innermost frame is where the machine is, the others are the
+    // callers of the calls on the stack, each named after the subroutine
+    // it is in
+    fn stack_trace(&self) -> Value {
+        let calls = self.machine.stack.frames();
+        let mut frames = Vec::with_capacity(calls.len() + 1);
+        let subroutine = |depth: usize| match depth {
+            0 => String::from("main"),
+            depth => format!("sub_{:03X}", calls[depth - 1].callee),
+        };
+
+        frames.push(self.frame(0, subroutine(calls.len()), self.machine.pc as u16));
+        for (depth, call) in calls.iter().enumerate().rev() {
+            frames.push(self.frame(calls.len() - depth, subroutine(depth), call.caller));
+        }
+        json!({ "stackFrames": frames, "totalFrames": calls.len() + 1 })
+    }
+
+    fn frame(&self, id: usize, name: String, address: u16) -> Value {
+        let mut frame = json!({
+            "id": id,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": reference(address),
+        });
+        if let Some(mapping) = self.source_map.as_ref().and_then(|map| map.location_of(address)) {
+            frame["source"] = json!({
+                "name": mapping.file.file_name().map(|n| n.to_string_lossy()),
+                "path": mapping.file.to_string_lossy(),
+            });
+            frame["line"] = Value::from(mapping.line);
+            frame["column"] = Value::from(1);
+        }
+        frame
+    }
+
+    fn variables(&self, reference: u64) -> Value {
+        let m = &self.machine;
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let pointer = |name: &str, address: u16| json!({
+            "name": name,
+            "value": self::reference(address),
+            "variablesReference": 0,
+            "memoryReference": self::reference(address),
+        });
+
+        let variables: Vec<Value> = match reference {
+            REGISTERS_REFERENCE => {
+                let mut variables: Vec<Value> = m.registers.iter().enumerate()
+                    .map(|(r, value)| variable(format!("V{:X}", r), format!("0x{:02X}", value)))
+                    .collect();
+                variables.push(pointer("I", m.i));
+                variables.push(pointer("PC", m.pc as u16));
+                variables.push(variable(String::from("DT"), m.delay_timer.to_string()));
+                variables.push(variable(String::from("ST"), m.sound_timer.to_string()));
+                variables
+            }
+            STACK_REFERENCE => m.stack.frames().iter().enumerate().rev()
+                .map(|(depth, frame)| json!({
+                    "name": format!("#{}", depth),
+                    "value": format!("{} -> {}", self::reference(frame.caller), self::reference(frame.callee)),
+                    "variablesReference": 0,
+                    "memoryReference": self::reference(frame.callee),
+                }))
+                .collect(),
+            _ => Vec::new(),
+        };
+        json!({ "variables": variables })
+    }
+
+    // Bytes past the end of memory are reported unreadable
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let start = args["memoryReference"].as_str()
+            .and_then(parse_reference)
+            .ok_or("Not a memory reference")?
+            .checked_add(args["offset"].as_i64().unwrap_or(0))
+            .filter(|start| (0..=u16::MAX as i64).contains(start))
+            .ok_or("Not an address in memory")?;
+        let count = args["count"].as_i64().unwrap_or(0).max(0);
+        let end = start.checked_add(count).ok_or("Not an address in memory")?;
+        // Bytes past the end of memory are unreadable
+        let memory = self.machine.memory();
+        let first = start.min(memory.len() as i64);
+        let last = end.clamp(first, memory.len() as i64);
+
+        Ok(json!({
+            "address": reference(start as u16),
+            "data": base64(&memory[first as usize..last as usize]),
+            "unreadableBytes": count - (last - first),
+        }))
+    }
+
+    fn is_breakpoint(&self, address: u16) -> bool {
+        self.instruction_breakpoints.contains(&address)
+            || self.source_breakpoints.values().any(|addresses| addresses.contains(&address))
+    }
+
+    fn is_call(&self) -> bool {
+        let memory = self.machine.memory();
+        match memory.get(self.machine.pc..self.machine.pc + 2) {
+            Some(&[high, low]) => matches!(Opcode::decode(u16::from_be_bytes([high, low])), Opcode::Call(_)),
+            _ => false,
+        }
+    }
+
+    // Runs a single instruction, waiting out the vblank a draw waits for
+    fn step(&mut self) {
+        if self.machine.is_waiting_vblank() {
+            self.machine.tick_timers();
+        }
+        match self.machine.run(self.machine.key_map) {
+            Ok(_) => self.stop("step", None),
+            Err(why) => self.stop("exception", Some(why)),
+        }
+    }
+
+    fn resume(&mut self, mode: Mode) {
+        self.resumed_at = Some(self.machine.pc as u16);
+        self.mode = mode;
+    }
+
+    fn stop(&mut self, reason: &str, error: Option<&str>) {
+        self.mode = Mode::Stopped;
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(error) = error {
+            body["description"] = Value::from(error);
+            body["text"] = Value::from(error);
+        }
+        self.event("stopped", body);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.outgoing.push(json!({ "type": "event", "event": event, "body": body }));
+    }
+}
+
+// Memory and instruction references are addresses in hexadecimal
+fn reference(address: u16) -> String {
+    format!("0x{:03X}", address)
+}
+
+fn parse_reference(reference: &str) -> Option<i64> {
+    match reference.strip_prefix("0x").or_else(|| reference.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => reference.parse().ok(),
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let word = chunk.iter().enumerate().fold(0u32, |word, (i, &b)| word | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(word >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Cursor;
+
+    fn request(seq: u64, command: &str, arguments: Value) -> Value {
+        json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })
+    }
+
+    fn find<'a>(messages: &'a [Value], kind: &str, name: &str) -> &'a Value {
+        let key = if kind == "event" { "event" } else { "command" };
+        messages.iter().find(|m| m["type"] == kind && m[key] == name).unwrap()
+    }
+
+    #[test]
+    fn messages_are_framed_by_their_length() {
+        let mut written = Vec::new();
+        let message = request(1, "threads", json!({ "text": "é" }));
+        write_message(&mut written, &message).unwrap();
+        write_message(&mut written, &message).unwrap();
+        assert!(written.starts_with(format!("Content-Length: {}\r\n\r\n", message.to_string().len()).as_bytes()));
+
+        let mut reader = Cursor::new(written);
+        assert_eq!(read_message(&mut reader).unwrap(), Some(message.clone()));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(message));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"Man!"), "TWFuIQ==");
+    }
+
+    #[test]
+    fn overflowing_addresses_are_rejected() {
+        let mut session = DebugSession::new();
+        session.handle(&request(1, "readMemory", json!({ "memoryReference": "0x7FFFFFFFFFFFFFFF", "offset": 1 })));
+        session.handle(&request(2, "readMemory", json!({ "memoryReference": "0x200", "count": i64::MAX })));
+        session.handle(&request(3, "setInstructionBreakpoints", json!({
+            "breakpoints": [{ "instructionReference": "0x7FFFFFFFFFFFFFFF", "offset": 1 }],
+        })));
+        session.handle(&request(4, "readMemory", json!({ "memoryReference": "0x002", "offset": -4, "count": 8 })));
+        let messages = session.take_messages();
+        assert_eq!(messages[0]["success"], false);
+        assert_eq!(messages[1]["success"], false);
+        assert_eq!(messages[2]["body"]["breakpoints"][0]["verified"], false);
+        assert_eq!(messages[3]["success"], false);
+    }
+
+    #[test]
+    fn a_session_breaks_on_source_lines_and_steps_over_calls() {
+        let directory = std::env::temp_dir().join(format!("chip8-dap-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        // main: 200 call sub / 202 V0 := 1 / 204 jump 204
+        // sub:  206 V1 := 2 / 208 return
+        let rom = [0x22, 0x06, 0x60, 0x01, 0x12, 0x04, 0x61, 0x02, 0x00, 0xEE];
+        fs::write(directory.join("game.ch8"), rom).unwrap();
+        fs::write(directory.join("game.json"), r#"{ "mappings": [
+            { "file": "game.8o", "line": 2, "address": "0x200" },
+            { "file": "game.8o", "line": 3, "address": "0x202" },
+            { "file": "game.8o", "line": 4, "address": "0x204" },
+            { "file": "game.8o", "line": 7, "address": "0x206" }
+        ] }"#).unwrap();
+
+        let mut session = DebugSession::new();
+        session.handle(&request(1, "initialize", json!({})));
+        session.handle(&request(2, "launch", json!({
+            "program": directory.join("game.ch8").to_str(),
+            "sourceMap": directory.join("game.json").to_str(),
+            "stopOnEntry": true,
+        })));
+        session.handle(&request(3, "setBreakpoints", json!({
+            "source": { "path": directory.join("game.8o").to_str() },
+            "breakpoints": [{ "line": 6 }, { "line": 20 }],
+        })));
+        session.handle(&request(4, "configurationDone", json!({})));
+        let messages = session.take_messages();
+        assert_eq!(find(&messages, "response", "launch")["success"], true);
+        let breakpoints = &find(&messages, "response", "setBreakpoints")["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["line"], 7);
+        assert_eq!(breakpoints[1]["verified"], false);
+        assert_eq!(find(&messages, "event", "stopped")["body"]["reason"], "entry");
+        assert!(!session.is_running());
+
+        session.handle(&request(5, "continue", json!({})));
+        session.run_frame();
+        assert_eq!(session.machine().pc, 0x206);
+        let messages = session.take_messages();
+        assert_eq!(find(&messages, "event", "stopped")["body"]["reason"], "breakpoint");
+
+        session.handle(&request(6, "stackTrace", json!({ "threadId": 1 })));
+        let messages = session.take_messages();
+        let frames = &find(&messages, "response", "stackTrace")["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "sub_206");
+        assert_eq!(frames[0]["line"], 7);
+        assert_eq!(frames[1]["name"], "main");
+        assert_eq!(frames[1]["instructionPointerReference"], "0x200");
+
+        // Out of the subroutine, over the next instruction and back to the
+        // start, stepping over the call
+        session.handle(&request(7, "stepOut", json!({ "threadId": 1 })));
+        while session.is_running() {
+            session.run_frame();
+        }
+        assert_eq!(session.machine().pc, 0x202);
+        session.handle(&request(8, "setBreakpoints", json!({ "source": { "path": directory.join("game.8o").to_str() }, "breakpoints": [] })));
+        session.handle(&request(9, "setInstructionBreakpoints", json!({
+            "breakpoints": [{ "instructionReference": "0x200", "offset": 2 }],
+        })));
+        session.machine.pc = 0x200;
+        session.handle(&request(10, "next", json!({ "threadId": 1 })));
+        while session.is_running() {
+            session.run_frame();
+        }
+        assert_eq!(session.machine().pc, 0x202);
+        assert_eq!(session.machine().registers[1], 2);
+        assert!(session.machine().stack.is_empty());
+
+        session.handle(&request(11, "variables", json!({ "variablesReference": REGISTERS_REFERENCE })));
+        session.handle(&request(12, "readMemory", json!({ "memoryReference": "0xFFE", "count": 4 })));
+        session.handle(&request(13, "disconnect", json!({})));
+        let messages = session.take_messages();
+        let variables = &find(&messages, "response", "variables")["body"]["variables"];
+        assert_eq!(variables[1]["value"], "0x02");
+        assert_eq!(variables[17]["memoryReference"], "0x202");
+        let memory = &find(&messages, "response", "readMemory")["body"];
+        assert_eq!((memory["data"].as_str(), memory["unreadableBytes"].as_i64()), (Some("AAA="), Some(2)));
+        assert!(session.is_finished());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
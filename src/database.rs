@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde_json::{Map, Value};
+use sha1::{Digest, Sha1};
+
+use crate::palette::Palette;
+use crate::quirks::Quirks;
+
+// Bundled programs.json of the community chip-8-database, replace it with the
+// full file from https://github.com/chip-8/chip-8-database to bundle every
+// known ROM
+const BUNDLED: &str = include_str!("../database/programs.json");
+
+// Quirk preset and instructions per frame of each platform id, after the
+// database's platforms.json
+const PLATFORMS: [(&str, &str, usize); 7] = [
+    ("originalChip8", "chip8", 15),
+    ("hybridVIP", "chip8", 15),
+    ("modernChip8", "default", 12),
+    ("chip48", "schip", 30),
+    ("superchip1", "schip", 30),
+    ("superchip", "schip", 30),
+    ("xochip", "xochip", 100),
+];
+
+/// Keypad keys a ROM uses for directions and its two action buttons, so
+/// that arrow keys and gamepads can press them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KeyHints {
+    pub up: Option<u8>,
+    pub down: Option<u8>,
+    pub left: Option<u8>,
+    pub right: Option<u8>,
+    pub a: Option<u8>,
+    pub b: Option<u8>,
+}
+
+/// What the database knows about a ROM. Settings it doesn't give are left
+/// to the frontend's defaults.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub release: Option<String>,
+    /// Platform id the ROM was written for, e.g. `superchip`
+    pub platform: Option<String>,
+    pub quirks: Option<Quirks>,
+    /// Instructions per 60 Hz frame
+    pub tickrate: Option<usize>,
+    pub palette: Option<Palette>,
+    pub keys: KeyHints,
+}
+
+impl Metadata {
+    /// Title and authors, e.g. "Pong by Paul Vervalin".
+    pub fn credits(&self) -> String {
+        if self.authors.is_empty() {
+            self.title.clone()
+        } else {
+            format!("{} by {}", self.title, self.authors.join(", "))
+        }
+    }
+}
+
+/// ROM metadata keyed by the SHA-1 of the ROM bytes, read from the
+/// programs.json file of the chip-8-database.
+#[derive(Clone, Debug, Default)]
+pub struct Database {
+    roms: HashMap<String, Metadata>,
+}
+
+impl Database {
+    pub fn bundled() -> Self {
+        Database::parse(BUNDLED).expect("The bundled database is invalid")
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = fs::read_to_string(path).map_err(|why| format!("Couldn't read {}: {}", path.display(), why))?;
+        Database::parse(&json).map_err(|why| format!("Couldn't parse {}: {}", path.display(), why))
+    }
+
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let programs: Value = serde_json::from_str(json).map_err(|why| why.to_string())?;
+        let programs = programs.as_array().ok_or("expected an array of programs")?;
+
+        let mut roms = HashMap::new();
+        for program in programs {
+            let title = program["title"].as_str().ok_or("program without a title")?;
+            let authors = program["authors"].as_array()
+                .map(|a| a.iter().filter_map(Value::as_str).map(String::from).collect())
+                .unwrap_or_default();
+            let release = program["release"].as_str().map(String::from);
+            let files = match program["roms"].as_object() {
+                Some(files) => files,
+                None => continue,
+            };
+
+            for (hash, rom) in files {
+                let platform = platform(rom);
+                let metadata = Metadata {
+                    title: String::from(title),
+                    authors: Vec::clone(&authors),
+                    release: release.clone(),
+                    quirks: platform.as_deref().and_then(|p| quirks(rom, p)),
+                    tickrate: rom["tickrate"].as_u64().map(|t| t as usize)
+                        .or_else(|| platform.as_deref().and_then(|p| PLATFORMS.iter().find(|(id, _, _)| *id == p)).map(|&(_, _, t)| t)),
+                    palette: palette(rom),
+                    keys: key_hints(rom),
+                    platform,
+                };
+                roms.insert(hash.to_ascii_lowercase(), metadata);
+            }
+        }
+        Ok(Database { roms })
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<&Metadata> {
+        self.roms.get(&sha1_hex(rom))
+    }
+
+    pub fn len(&self) -> usize {
+        self.roms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roms.is_empty()
+    }
+}
+
+/// Lowercase hexadecimal SHA-1 of `bytes`, as used for the database keys.
+pub fn sha1_hex(bytes: &[u8]) -> String {
+    Sha1::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// The preferred platform comes first, ROMs only listed as running on a
+// platform with some quirks changed fall back to the first of those
+fn platform(rom: &Value) -> Option<String> {
+    let listed = rom["platforms"].as_array().and_then(|p| p.first());
+    let quirky = rom["quirkyPlatforms"].as_object().and_then(|p| p.keys().next());
+    listed.and_then(Value::as_str).or(quirky.map(String::as_str)).map(String::from)
+}
+
+fn quirks(rom: &Value, platform: &str) -> Option<Quirks> {
+    let preset = PLATFORMS.iter().find(|(id, _, _)| *id == platform)?.1;
+    let mut quirks = Quirks::by_name(preset)?;
+    if let Some(changes) = rom["quirkyPlatforms"][platform].as_object() {
+        apply_quirks(&mut quirks, changes);
+    }
+    Some(quirks)
+}
+
+// The database names quirks after the deviation from the original
+// behaviour, so some of them are the opposite of ours
+fn apply_quirks(quirks: &mut Quirks, changes: &Map<String, Value>) {
+    for (name, value) in changes {
+        let on = match value.as_bool() {
+            Some(on) => on,
+            None => continue,
+        };
+        match name.as_str() {
+            "shift" => quirks.shift_vy = !on,
+            "memoryLeaveIUnchanged" => quirks.memory_increment = !on,
+            "memoryIncrementByX" => quirks.memory_increment = on,
+            "wrap" => quirks.clip_sprites = !on,
+            "jump" => quirks.jump_vx = on,
+            "vblank" => quirks.display_wait = on,
+            "logic" => quirks.vf_reset = on,
+            _ => {}
+        }
+    }
+}
+
+// Background and foreground of the first two pixel colors
+fn palette(rom: &Value) -> Option<Palette> {
+    let pixels = rom["colors"]["pixels"].as_array()?;
+    let background = Palette::parse_color(pixels.first()?.as_str()?)?;
+    let foreground = Palette::parse_color(pixels.get(1)?.as_str()?)?;
+    Some(Palette { background, foreground })
+}
+
+fn key_hints(rom: &Value) -> KeyHints {
+    let key = |name: &str| rom["keys"][name].as_u64().filter(|&k| k < 16).map(|k| k as u8);
+    KeyHints {
+        up: key("up"),
+        down: key("down"),
+        left: key("left"),
+        right: key("right"),
+        a: key("a"),
+        b: key("b"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAMS: &str = r##"[
+        {
+            "title": "Paddles",
+            "authors": ["Someone", "Someone Else"],
+            "release": "1990",
+            "roms": {
+                "A9993E364706816ABA3E25717850C26C9CD0D89D": {
+                    "file": "paddles.ch8",
+                    "platforms": ["superchip", "xochip"],
+                    "quirkyPlatforms": { "superchip": { "jump": false, "vblank": true } },
+                    "tickrate": 20,
+                    "colors": { "pixels": ["#000033", "#ffcc00"] },
+                    "keys": { "up": 1, "down": 4, "a": 6 }
+                }
+            }
+        },
+        {
+            "title": "Untitled",
+            "roms": { "0000000000000000000000000000000000000000": { "platforms": ["originalChip8"] } }
+        }
+    ]"##;
+
+    #[test]
+    fn roms_are_found_by_sha1() {
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+
+        let database = Database::parse(PROGRAMS).unwrap();
+        assert_eq!(database.len(), 2);
+        let metadata = database.lookup(b"abc").unwrap();
+        assert_eq!(metadata.credits(), "Paddles by Someone, Someone Else");
+        assert_eq!(metadata.platform.as_deref(), Some("superchip"));
+        assert_eq!(metadata.tickrate, Some(20));
+        assert_eq!(metadata.palette, Some(Palette { background: [0x00, 0x00, 0x33], foreground: [0xFF, 0xCC, 0x00] }));
+        assert_eq!(metadata.keys, KeyHints { up: Some(1), down: Some(4), a: Some(6), ..KeyHints::default() });
+        assert!(database.lookup(b"abd").is_none());
+    }
+
+    #[test]
+    fn platforms_pick_the_quirk_preset_and_tickrate() {
+        let database = Database::parse(PROGRAMS).unwrap();
+
+        let expected = Quirks { jump_vx: false, display_wait: true, ..Quirks::by_name("schip").unwrap() };
+        assert_eq!(database.lookup(b"abc").unwrap().quirks, Some(expected));
+
+        let untitled = &database.roms["0000000000000000000000000000000000000000"];
+        assert_eq!(untitled.quirks, Quirks::by_name("chip8"));
+        assert_eq!(untitled.tickrate, Some(15));
+        assert_eq!(untitled.credits(), "Untitled");
+    }
+
+    #[test]
+    fn the_bundled_database_parses() {
+        Database::bundled();
+    }
+}
@@ -0,0 +1,213 @@
+use crate::cpu::Machine;
+use crate::instruction::Opcode;
+use super::osd::{self, ADVANCE, HIGHLIGHT_COLOR, LINE_HEIGHT, MARGIN, TEXT_COLOR};
+
+// The panels are laid out on a grid of characters, in a picture five times
+// the size of the game's so that both can be stacked at the same width
+const COLUMNS: usize = 80;
+const ROWS: usize = 20;
+pub const DEBUGGER_WIDTH: usize = COLUMNS * ADVANCE;
+pub const DEBUGGER_HEIGHT: usize = ROWS * LINE_HEIGHT;
+
+// First column of each panel
+const DISASSEMBLY_COLUMN: usize = 0;
+const REGISTERS_COLUMN: usize = 23;
+const STACK_COLUMN: usize = 36;
+const MEMORY_COLUMN: usize = 47;
+
+// Instructions shown before the one at pc
+const DISASSEMBLY_CONTEXT: usize = 6;
+const MEMORY_ROW_BYTES: usize = 8;
+// Memory rows shown before the one holding I
+const MEMORY_CONTEXT: usize = 4;
+
+// Keypad keys as laid out on the COSMAC VIP
+const KEYPAD: [[usize; 4]; 4] = [[0x1, 0x2, 0x3, 0xC], [0x4, 0x5, 0x6, 0xD], [0x7, 0x8, 0x9, 0xE], [0xA, 0x0, 0xB, 0xF]];
+
+const BACKGROUND_COLOR: [u8; 4] = [24, 24, 24, 255];
+const TITLE_COLOR: [u8; 4] = [136, 136, 136, 255];
+
+/// Renders the debugger panels into `rgba`, a `DEBUGGER_WIDTH` x
+/// `DEBUGGER_HEIGHT` RGBA picture: the disassembly around pc, the
+/// registers, the keypad, the stack and the memory at I, the bytes the
+/// instruction at pc reads or writes there highlighted.
+pub fn render_debugger(machine: &Machine, keypad: &[bool; 16], rgba: &mut [u8]) {
+    osd::fill(rgba, DEBUGGER_WIDTH, 0, 0, DEBUGGER_WIDTH, DEBUGGER_HEIGHT, BACKGROUND_COLOR);
+    let memory = machine.memory();
+    let opcode = |address: usize| match memory.get(address..address + 2) {
+        Some(&[high, low]) => Some((u16::from_be_bytes([high, low]), Opcode::decode(u16::from_be_bytes([high, low])))),
+        _ => None,
+    };
+
+    title(rgba, DISASSEMBLY_COLUMN, "Disassembly");
+    for row in 1..ROWS {
+        let address = match disassembly_address(machine.pc, row) {
+            Some(address) => address,
+            None => continue,
+        };
+        if let Some((raw, decoded)) = opcode(address) {
+            let line = format!("{:03X} {:04X} {}", address, raw, decoded);
+            text(rgba, DISASSEMBLY_COLUMN, row, &line, address == machine.pc);
+        }
+    }
+
+    title(rgba, REGISTERS_COLUMN, "Registers");
+    for r in 0..8 {
+        let line = format!("V{:X} {:02X}  V{:X} {:02X}", r, machine.registers[r], r + 8, machine.registers[r + 8]);
+        text(rgba, REGISTERS_COLUMN, r + 1, &line, false);
+    }
+    text(rgba, REGISTERS_COLUMN, 10, &format!("I {:03X} PC {:03X}", machine.i, machine.pc), false);
+    text(rgba, REGISTERS_COLUMN, 11, &format!("DT {:02X} ST {:02X}", machine.delay_timer, machine.sound_timer), false);
+
+    title_at(rgba, REGISTERS_COLUMN, 13, "Keypad");
+    for (row, keys) in KEYPAD.iter().enumerate() {
+        for (column, &key) in keys.iter().enumerate() {
+            text(rgba, REGISTERS_COLUMN + 2 * column, row + 14, &format!("{:X}", key), keypad[key]);
+        }
+    }
+
+    title(rgba, STACK_COLUMN, "Stack");
+    if machine.stack.is_empty() {
+        title_at(rgba, STACK_COLUMN, 1, "Empty");
+    }
+    for (depth, frame) in machine.stack.frames().iter().enumerate().take(ROWS - 1) {
+        let line = format!("{:X} {:03X}>{:03X}", depth, frame.caller, frame.callee);
+        text(rgba, STACK_COLUMN, depth + 1, &line, false);
+    }
+
+    // Rows of memory from a few before the one holding I
+    let i = machine.i as usize;
+    let length = opcode(machine.pc).map_or(1, |(_, decoded)| bytes_at_i(decoded));
+    let last_row = memory.len() / MEMORY_ROW_BYTES - (ROWS - 1);
+    let first_row = (i / MEMORY_ROW_BYTES).saturating_sub(MEMORY_CONTEXT).min(last_row);
+    title(rgba, MEMORY_COLUMN, &format!("Memory at I {:03X}", i));
+    for row in 1..ROWS {
+        let start = (first_row + row - 1) * MEMORY_ROW_BYTES;
+        text(rgba, MEMORY_COLUMN, row, &format!("{:03X}", start), false);
+        for (offset, byte) in memory[start..start + MEMORY_ROW_BYTES].iter().enumerate() {
+            let address = start + offset;
+            let highlighted = address >= i && address < i + length;
+            text(rgba, MEMORY_COLUMN + 4 + 3 * offset, row, &format!("{:02X}", byte), highlighted);
+        }
+    }
+}
+
+// Address of the instruction shown on a row of the disassembly, none for
+// the rows before the start of memory
+fn disassembly_address(pc: usize, row: usize) -> Option<usize> {
+    (pc + 2 * (row - 1)).checked_sub(2 * DISASSEMBLY_CONTEXT)
+}
+
+// How many bytes from I an instruction accesses, the byte at I for those
+// that don't use it
+fn bytes_at_i(opcode: Opcode) -> usize {
+    match opcode {
+        Opcode::Display(_, _, n) => (n as usize).max(1),
+        Opcode::Bcd(_) => 3,
+        Opcode::RegDump(x) | Opcode::RegLoad(x) => x as usize + 1,
+        _ => 1,
+    }
+}
+
+fn title(rgba: &mut [u8], column: usize, title: &str) {
+    title_at(rgba, column, 0, title);
+}
+
+fn title_at(rgba: &mut [u8], column: usize, row: usize, title: &str) {
+    osd::draw_text(rgba, DEBUGGER_WIDTH, title, x(column), y(row), TITLE_COLOR, BACKGROUND_COLOR);
+}
+
+// Highlighted text is drawn in inverted colors
+fn text(rgba: &mut [u8], column: usize, row: usize, text: &str, highlighted: bool) {
+    let (color, background) = if highlighted { (HIGHLIGHT_COLOR, TEXT_COLOR) } else { (TEXT_COLOR, BACKGROUND_COLOR) };
+    osd::draw_text(rgba, DEBUGGER_WIDTH, text, x(column), y(row), color, background);
+}
+
+fn x(column: usize) -> usize {
+    MARGIN + column * ADVANCE
+}
+
+fn y(row: usize) -> usize {
+    MARGIN + row * LINE_HEIGHT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Chip8Machine;
+
+    fn render(program: &[u8], pc: usize, i: u16) -> Vec<u8> {
+        let mut machine = Machine::new();
+        machine.load_rom(program).unwrap();
+        machine.pc = pc;
+        machine.i = i;
+        let mut rgba = vec![0; DEBUGGER_WIDTH * DEBUGGER_HEIGHT * 4];
+        render_debugger(&machine, &[false; 16], &mut rgba);
+        rgba
+    }
+
+    // Highlighted text is on a box of the text color, starting a pixel
+    // above and left of it
+    fn is_highlighted(rgba: &[u8], column: usize, row: usize) -> bool {
+        let offset = ((y(row) - 1) * DEBUGGER_WIDTH + x(column) - 1) * 4;
+        rgba[offset..offset + 4] == TEXT_COLOR
+    }
+
+    // Nothing drawn in the first character of the text
+    fn is_blank(rgba: &[u8], column: usize, row: usize) -> bool {
+        (y(row)..y(row) + LINE_HEIGHT - 1).all(|py| {
+            (x(column)..x(column) + ADVANCE).all(|px| {
+                let offset = (py * DEBUGGER_WIDTH + px) * 4;
+                rgba[offset..offset + 4] == BACKGROUND_COLOR
+            })
+        })
+    }
+
+    // The memory cells from I taken by the highlight, I being far enough
+    // from the ends of memory to be on the fifth row
+    fn highlighted_bytes(rgba: &[u8], i: usize) -> usize {
+        (0..MEMORY_ROW_BYTES * 2)
+            .take_while(|n| {
+                let row = MEMORY_CONTEXT + 1 + (i % MEMORY_ROW_BYTES + n) / MEMORY_ROW_BYTES;
+                is_highlighted(rgba, MEMORY_COLUMN + 4 + 3 * ((i + n) % MEMORY_ROW_BYTES), row)
+            })
+            .count()
+    }
+
+    #[test]
+    fn the_bytes_an_instruction_uses_are_highlighted() {
+        assert_eq!(bytes_at_i(Opcode::decode(0xD015)), 5);
+        assert_eq!(bytes_at_i(Opcode::decode(0xD010)), 1);
+        assert_eq!(bytes_at_i(Opcode::decode(0xF333)), 3);
+        assert_eq!(bytes_at_i(Opcode::decode(0xF355)), 4);
+        assert_eq!(bytes_at_i(Opcode::decode(0x00E0)), 1);
+
+        // Sprites, BCD and register dumps, from I = 0x306 across two rows
+        assert_eq!(highlighted_bytes(&render(&[0xD0, 0x15], 0x200, 0x306), 0x306), 5);
+        assert_eq!(highlighted_bytes(&render(&[0xF3, 0x33], 0x200, 0x306), 0x306), 3);
+        assert_eq!(highlighted_bytes(&render(&[0xF3, 0x55], 0x200, 0x306), 0x306), 4);
+        assert_eq!(highlighted_bytes(&render(&[0x00, 0xE0], 0x200, 0x306), 0x306), 1);
+    }
+
+    #[test]
+    fn the_disassembly_stops_at_the_ends_of_memory() {
+        assert_eq!(disassembly_address(4, 1), None);
+        assert_eq!(disassembly_address(4, DISASSEMBLY_CONTEXT - 2), None);
+        assert_eq!(disassembly_address(4, DISASSEMBLY_CONTEXT - 1), Some(0));
+        assert_eq!(disassembly_address(0xFFE, DISASSEMBLY_CONTEXT + 1), Some(0xFFE));
+
+        let pc_row = DISASSEMBLY_CONTEXT + 1;
+        let rgba = render(&[], 4, 0);
+        assert!(is_blank(&rgba, DISASSEMBLY_COLUMN, pc_row - 3));
+        assert!(!is_blank(&rgba, DISASSEMBLY_COLUMN, pc_row - 2));
+        assert!(!is_highlighted(&rgba, DISASSEMBLY_COLUMN, pc_row - 2));
+        assert!(is_highlighted(&rgba, DISASSEMBLY_COLUMN, pc_row));
+
+        // The last instruction fits, nothing is shown past it
+        let rgba = render(&[], 0xFFE, 0);
+        assert!(is_highlighted(&rgba, DISASSEMBLY_COLUMN, pc_row));
+        assert!(is_blank(&rgba, DISASSEMBLY_COLUMN, pc_row + 1));
+        let rgba = render(&[], 0xFFF, 0);
+        assert!(is_blank(&rgba, DISASSEMBLY_COLUMN, pc_row));
+    }
+}
@@ -0,0 +1,100 @@
+/// Optional CRT-like post-processing applied in software to the picture
+/// before it is uploaded, so it works without any GPU support. Each effect is
+/// disabled with `None` or enabled with a strength between 0 and 1.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Effects {
+    /// Darkens every other row of the output like the gaps between scanlines
+    pub scanlines: Option<f32>,
+    /// Darkens the border of each emulated pixel, drawing a thin grid
+    pub grid: Option<f32>,
+    /// Lit pixels glow onto their neighbours
+    pub bloom: Option<f32>,
+    /// Darkens the picture towards its corners
+    pub vignette: Option<f32>,
+}
+
+impl Effects {
+    /// A reasonable combination of all effects.
+    pub fn crt() -> Self {
+        Effects {
+            scanlines: Some(0.35),
+            grid: Some(0.15),
+            bloom: Some(0.25),
+            vignette: Some(0.3),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.scanlines.is_some() || self.grid.is_some() || self.bloom.is_some() || self.vignette.is_some()
+    }
+
+    /// Scales the `width` x `height` RGB24 picture in `src` up by `scale`
+    /// into `dst`, applying the enabled effects on the way.
+    pub fn apply(&self, src: &[u8], width: usize, height: usize, scale: usize, dst: &mut Vec<u8>) {
+        let out_width = width * scale;
+        let out_height = height * scale;
+        dst.resize(out_width * out_height * 3, 0);
+
+        let glow = match self.bloom {
+            Some(strength) => blur(src, width, height, strength),
+            None => Vec::new(),
+        };
+
+        for out_y in 0..out_height {
+            let (y, sub_y) = (out_y / scale, out_y % scale);
+            for out_x in 0..out_width {
+                let (x, sub_x) = (out_x / scale, out_x % scale);
+
+                let mut factor = 1.0;
+                if let Some(strength) = self.scanlines {
+                    if out_y % 2 == 1 {
+                        factor *= 1.0 - strength;
+                    }
+                }
+                if let Some(strength) = self.grid {
+                    if sub_x == 0 || sub_y == 0 {
+                        factor *= 1.0 - strength;
+                    }
+                }
+                if let Some(strength) = self.vignette {
+                    let dx = (out_x as f32 + 0.5) / out_width as f32 * 2.0 - 1.0;
+                    let dy = (out_y as f32 + 0.5) / out_height as f32 * 2.0 - 1.0;
+                    factor *= 1.0 - strength * (dx * dx + dy * dy) / 2.0;
+                }
+
+                let src_offset = (y * width + x) * 3;
+                let dst_offset = (out_y * out_width + out_x) * 3;
+                for c in 0..3 {
+                    let mut value = src[src_offset + c] as f32 * factor;
+                    if !glow.is_empty() {
+                        value += glow[src_offset + c];
+                    }
+                    dst[dst_offset + c] = value.round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+    }
+}
+
+// 3x3 box blur of the picture, scaled by `strength`
+fn blur(src: &[u8], width: usize, height: usize, strength: f32) -> Vec<f32> {
+    let mut glow = vec![0.0; src.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = [0.0; 3];
+            for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                    let offset = (ny * width + nx) * 3;
+                    for (c, s) in sum.iter_mut().enumerate() {
+                        *s += src[offset + c] as f32;
+                    }
+                }
+            }
+            let offset = (y * width + x) * 3;
+            for (c, s) in sum.iter().enumerate() {
+                glow[offset + c] = s / 9.0 * strength;
+            }
+        }
+    }
+    glow
+}
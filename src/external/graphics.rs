@@ -1,15 +1,73 @@
 use sdl2;
 use sdl2::pixels;
+use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Rect;
-use sdl2::render::Canvas;
-use sdl2::video::Window;
+use sdl2::render::{BlendMode, Canvas, Texture};
+use sdl2::video::{FullscreenType, Window};
+
+use std::time::Instant;
+
+use crate::cpu::{DirtyRect, Machine, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::palette::Palette;
+use super::debugger::{self, DEBUGGER_HEIGHT, DEBUGGER_WIDTH};
+use super::effects::Effects;
+use super::menu::Panel;
+use super::osd::{Osd, OSD_HEIGHT, OSD_WIDTH};
+use super::viewport::viewport;
 
 const SCALE_FACTOR: u32 = 20;
-const SCREEN_WIDTH: u32 = (64 as u32) * SCALE_FACTOR;
-const SCREEN_HEIGHT: u32 = (32 as u32) * SCALE_FACTOR;
+const MAX_SCALE_FACTOR: u32 = 40;
+// Texture pixels per emulated pixel when post-processing effects are enabled
+const EFFECTS_SCALE: usize = 8;
+// Bytes per row of the RGB24 framebuffer uploaded to the texture
+const PITCH: usize = SCREEN_WIDTH * 3;
+
+// Length of a 60 Hz frame in seconds, the unit decay rates are expressed in
+const FRAME_SECONDS: f32 = 1.0 / 60.0;
+// Below this brightness a fading pixel is considered off
+const FADE_CUTOFF: f32 = 0.02;
+
+/// How pixels that switch off are shown, to hide the flicker caused by
+/// games erasing and redrawing their sprites every frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Persistence {
+    /// Pixels switch off instantly, exactly as the framebuffer says
+    Off,
+    /// Pixels fade out like phosphor, losing this fraction (0, 1] of their
+    /// brightness every 60 Hz frame
+    Decay(f32),
+    /// Each pixel shows the average of the last two framebuffers
+    Blend,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Persistence::Decay(0.4)
+    }
+}
 
 pub struct Screen {
     canvas: Canvas<Window>,
+    texture: Texture,
+    scale: u32,
+    integer_scaling: bool,
+    rgb: Vec<u8>,
+    palette: Palette,
+    effects: Effects,
+    processed: Vec<u8>,
+    persistence: Persistence,
+    intensity: [[f32; SCREEN_HEIGHT]; SCREEN_WIDTH],
+    previous: [[bool; SCREEN_HEIGHT]; SCREEN_WIDTH],
+    last_draw: Instant,
+    osd: Osd,
+    // Text rendered by the OSD, blended over the picture
+    overlay: Texture,
+    overlay_rgba: Vec<u8>,
+    // Debugger panels shown below the picture, as wide as it
+    debugger_shown: bool,
+    debugger_changed: bool,
+    debugger: Texture,
+    debugger_rgba: Vec<u8>,
 }
 
 impl Screen {
@@ -18,10 +76,11 @@ impl Screen {
         let window = video_subsys
             .window(
                 "Chip 8 Emulator",
-                SCREEN_WIDTH,
-                SCREEN_HEIGHT,
+                SCREEN_WIDTH as u32 * SCALE_FACTOR,
+                SCREEN_HEIGHT as u32 * SCALE_FACTOR,
             )
             .position_centered()
+            .resizable()
             .opengl()
             .build()
             .unwrap();
@@ -32,26 +91,278 @@ impl Screen {
         canvas.clear();
         canvas.present();
 
-        Screen { canvas: canvas }
+        let texture = create_texture(&canvas, 1);
+        let mut overlay = canvas
+            .texture_creator()
+            .create_texture_streaming(PixelFormatEnum::RGBA32, OSD_WIDTH as u32, OSD_HEIGHT as u32)
+            .unwrap();
+        overlay.set_blend_mode(BlendMode::Blend);
+        let debugger = canvas
+            .texture_creator()
+            .create_texture_streaming(PixelFormatEnum::RGBA32, DEBUGGER_WIDTH as u32, DEBUGGER_HEIGHT as u32)
+            .unwrap();
+
+        Screen {
+            canvas,
+            texture,
+            scale: SCALE_FACTOR,
+            integer_scaling: false,
+            rgb: vec![0; PITCH * SCREEN_HEIGHT],
+            palette: Palette::default(),
+            effects: Effects::default(),
+            processed: Vec::new(),
+            persistence: Persistence::default(),
+            intensity: [[0.0; SCREEN_HEIGHT]; SCREEN_WIDTH],
+            previous: [[false; SCREEN_HEIGHT]; SCREEN_WIDTH],
+            last_draw: Instant::now(),
+            osd: Osd::new(),
+            overlay,
+            overlay_rgba: vec![0; OSD_WIDTH * OSD_HEIGHT * 4],
+            debugger_shown: false,
+            debugger_changed: false,
+            debugger,
+            debugger_rgba: vec![0; DEBUGGER_WIDTH * DEBUGGER_HEIGHT * 4],
+        }
+    }
+
+    pub fn set_persistence(&mut self, persistence: Persistence) {
+        self.persistence = persistence;
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        for (x, col) in self.intensity.iter().enumerate() {
+            for (y, &intensity) in col.iter().enumerate() {
+                let offset = y * PITCH + x * 3;
+                self.rgb[offset..offset + 3].copy_from_slice(&palette.color(intensity));
+            }
+        }
+        self.upload(DirtyRect::full());
+        self.present();
+    }
+
+    pub fn palette(&self) -> Palette {
+        self.palette
+    }
+
+    pub fn set_effects(&mut self, effects: Effects) {
+        let scale = if effects.is_enabled() { EFFECTS_SCALE } else { 1 };
+        if scale != self.texture_scale() {
+            self.texture = create_texture(&self.canvas, scale);
+        }
+        self.effects = effects;
+        self.upload(DirtyRect::full());
+        self.present();
+    }
+
+    pub fn effects(&self) -> Effects {
+        self.effects
+    }
+
+    /// Only scale the image by whole multiples so every emulated pixel is
+    /// exactly the same size, at the cost of wider borders.
+    pub fn set_integer_scaling(&mut self, integer_scaling: bool) {
+        self.integer_scaling = integer_scaling;
+        self.present();
+    }
+
+    pub fn integer_scaling(&self) -> bool {
+        self.integer_scaling
+    }
+
+    /// Resizes the window to `scale` window pixels per emulated pixel.
+    /// Ignored while in fullscreen.
+    pub fn set_scale(&mut self, scale: u32) {
+        self.scale = scale.clamp(1, MAX_SCALE_FACTOR);
+        if self.canvas.window().fullscreen_state() != FullscreenType::Off {
+            return;
+        }
+        let (width, height) = self.layout_size();
+        let _ = self.canvas.window_mut().set_size(width * self.scale, height * self.scale);
+        self.present();
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        let state = match self.canvas.window().fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        let _ = self.canvas.window_mut().set_fullscreen(state);
+        self.present();
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        let _ = self.canvas.window_mut().set_title(title);
+    }
+
+    /// Shows `text` over the picture for a couple of seconds.
+    pub fn notify(&mut self, text: &str) {
+        self.osd.notify(text);
+    }
+
+    /// Text permanently shown in the top right corner, `None` hiding it.
+    pub fn set_status(&mut self, status: Option<&str>) {
+        self.osd.set_status(status);
+    }
+
+    /// Shows a menu over the picture, `None` hiding it.
+    pub fn set_panel(&mut self, panel: Option<Panel>) {
+        self.osd.set_panel(panel);
+    }
+
+    /// Shows or hides the debugger panels below the picture, growing or
+    /// shrinking the window to make room for them.
+    pub fn set_debugger_shown(&mut self, shown: bool) {
+        self.debugger_shown = shown;
+        self.set_scale(self.scale);
+        self.present();
+    }
+
+    pub fn debugger_shown(&self) -> bool {
+        self.debugger_shown
+    }
+
+    /// Renders the debugger panels for the current state of `machine` and
+    /// the keypad, presented by the next `draw`. Ignored while they are
+    /// hidden.
+    pub fn update_debugger(&mut self, machine: &Machine, keypad: &[bool; 16]) {
+        if !self.debugger_shown {
+            return;
+        }
+        debugger::render_debugger(machine, keypad, &mut self.debugger_rgba);
+        let _ = self.debugger.update(None, &self.debugger_rgba, DEBUGGER_WIDTH * 4);
+        self.debugger_changed = true;
+    }
+
+    /// Presents the last drawn picture again, e.g. after the window was
+    /// resized or exposed.
+    pub fn refresh(&mut self) {
+        self.present();
+    }
+
+    /// Whether some pixel is still fading out and the screen needs to be
+    /// redrawn even though the framebuffer did not change.
+    pub fn is_fading(&self) -> bool {
+        self.intensity.iter().flatten().any(|&i| i > 0.0 && i < 1.0)
     }
 
-    pub fn draw(&mut self, pixels: &[[bool; 32]; 64]) {
-        for (x, col) in pixels.iter().enumerate() {
-            for (y, &p) in col.iter().enumerate() {
-                let x = (x as u32) * SCALE_FACTOR;
-                let y = (y as u32) * SCALE_FACTOR;
-                self.canvas.set_draw_color(color(p));
-                let _ = self.canvas
-                    .fill_rect(Rect::new(x as i32, y as i32, SCALE_FACTOR, SCALE_FACTOR));
+    /// Presents the framebuffer, the OSD and the debugger panels, called
+    /// once per 60 Hz frame. Only the `dirty` region is recomputed and
+    /// uploaded unless pixels are fading, and nothing is done when neither
+    /// the picture, the OSD nor the panels changed at all.
+    pub fn draw(&mut self, pixels: &[[bool; SCREEN_HEIGHT]; SCREEN_WIDTH], dirty: Option<DirtyRect>) {
+        let osd_changed = self.osd.update();
+        if osd_changed {
+            self.osd.render(&mut self.overlay_rgba);
+            let _ = self.overlay.update(None, &self.overlay_rgba, OSD_WIDTH * 4);
+        }
+        let debugger_changed = std::mem::take(&mut self.debugger_changed);
+
+        let region = match (self.is_fading(), dirty) {
+            (true, _) => DirtyRect::full(),
+            (false, Some(dirty)) => dirty,
+            (false, None) => {
+                if osd_changed || debugger_changed {
+                    self.present();
+                }
+                return;
             }
+        };
+
+        let frames = self.last_draw.elapsed().as_secs_f32() / FRAME_SECONDS;
+        self.last_draw = Instant::now();
+
+        for (x, col) in pixels.iter().enumerate().skip(region.x).take(region.width) {
+            for (y, &p) in col.iter().enumerate().skip(region.y).take(region.height) {
+                let intensity = match self.persistence {
+                    Persistence::Off => if p { 1.0 } else { 0.0 },
+                    Persistence::Decay(decay) => decayed(self.intensity[x][y], p, decay, frames),
+                    Persistence::Blend => match (p, self.previous[x][y]) {
+                        (true, true) => 1.0,
+                        (false, false) => 0.0,
+                        _ => 0.5,
+                    },
+                };
+                self.intensity[x][y] = intensity;
+                self.previous[x][y] = p;
+
+                let offset = y * PITCH + x * 3;
+                self.rgb[offset..offset + 3].copy_from_slice(&self.palette.color(intensity));
+            }
+        }
+
+        self.upload(region);
+        self.present();
+    }
+
+    // Copies `region` of the RGB framebuffer to the texture, post-processing
+    // the whole picture instead when effects are enabled
+    fn upload(&mut self, region: DirtyRect) {
+        if self.effects.is_enabled() {
+            self.effects.apply(&self.rgb, SCREEN_WIDTH, SCREEN_HEIGHT, EFFECTS_SCALE, &mut self.processed);
+            let _ = self.texture.update(None, &self.processed, PITCH * EFFECTS_SCALE);
+        } else {
+            let rect = Rect::new(region.x as i32, region.y as i32, region.width as u32, region.height as u32);
+            let start = region.y * PITCH + region.x * 3;
+            let _ = self.texture.update(rect, &self.rgb[start..], PITCH);
+        }
+    }
+
+    fn texture_scale(&self) -> usize {
+        self.texture.query().width as usize / SCREEN_WIDTH
+    }
+
+    // Size of the emulated picture, regardless of the texture resolution
+    fn image_size(&self) -> (u32, u32) {
+        let query = self.texture.query();
+        let scale = self.texture_scale() as u32;
+        (query.width / scale, query.height / scale)
+    }
+
+    // Size of the picture and the debugger panels below it, which have the
+    // same aspect ratio
+    fn layout_size(&self) -> (u32, u32) {
+        let (width, height) = self.image_size();
+        if self.debugger_shown { (width, height * 2) } else { (width, height) }
+    }
+
+    fn present(&mut self) {
+        let (x, y, width, height) = viewport(self.canvas.output_size().unwrap_or((0, 0)), self.layout_size(), self.integer_scaling);
+        let mut target = Rect::new(x, y, width, height);
+        self.canvas.clear();
+        if self.debugger_shown {
+            let height = target.height() / 2;
+            let panels = Rect::new(target.x(), target.y() + height as i32, target.width(), target.height() - height);
+            let _ = self.canvas.copy(&self.debugger, None, panels);
+            target.set_height(height);
+        }
+        let _ = self.canvas.copy(&self.texture, None, target);
+        if !self.osd.is_empty() {
+            let _ = self.canvas.copy(&self.overlay, None, target);
         }
         self.canvas.present();
     }
 }
 
-fn color(value: bool) -> pixels::Color {
-    match value {
-        true => pixels::Color::RGB(255, 255, 255),
-        false => pixels::Color::RGB(0, 0, 0)
+fn create_texture(canvas: &Canvas<Window>, scale: usize) -> Texture {
+    canvas
+        .texture_creator()
+        .create_texture_streaming(
+            PixelFormatEnum::RGB24,
+            (SCREEN_WIDTH * scale) as u32,
+            (SCREEN_HEIGHT * scale) as u32,
+        )
+        .unwrap()
+}
+
+fn decayed(intensity: f32, lit: bool, decay: f32, frames: f32) -> f32 {
+    if lit {
+        return 1.0;
     }
-}
\ No newline at end of file
+    let faded = intensity * (1.0 - decay.clamp(0.0, 1.0)).powf(frames);
+    if faded < FADE_CUTOFF { 0.0 } else { faded }
+}
@@ -1,23 +1,134 @@
 use sdl2;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::controller::{Button, GameController};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::GameControllerSubsystem;
+
+use super::menu::MenuInput;
+use crate::database::KeyHints;
+
+/// Frontend actions triggered by hotkeys or window events, as opposed to
+/// the emulated keypad.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    ToggleFullscreen,
+    ToggleIntegerScaling,
+    ScaleUp,
+    ScaleDown,
+    ToggleEffects,
+    NextPalette,
+    // Save the screen as PNG, at native resolution or at the window scale
+    Screenshot { native: bool },
+    ToggleRecording,
+    // Restart the ROM, a hard reset also clearing all memory
+    Reset { hard: bool },
+    ToggleTurbo,
+    // Cycle through the slow motion speeds
+    SlowMotion,
+    TogglePause,
+    // Pause, or run a single frame when paused
+    FrameAdvance,
+    // Pause, or run a single instruction when paused
+    Step,
+    // Show or hide the debugger panels
+    ToggleDebugger,
+    // Show or hide the frames and instructions per second
+    ToggleCounter,
+    // Open or close the pause menu
+    ToggleMenu,
+    // Navigation while the menu is open
+    Menu(MenuInput),
+    // The window was resized or exposed and must be presented again
+    Redraw,
+}
 
 pub struct Input {
     events: sdl2::EventPump,
+    commands: Vec<Command>,
+    // Gamepads navigate the menu, SDL only reports those kept open here
+    controller_subsystem: Option<GameControllerSubsystem>,
+    controllers: Vec<GameController>,
+    // Keypad keys pressed by the arrow keys and gamepad buttons
+    key_hints: KeyHints,
 }
 
 impl Input {
     pub fn new(sdl_context: &sdl2::Sdl) -> Self {
-        Input { events: sdl_context.event_pump().unwrap() }
+        Input {
+            events: sdl_context.event_pump().unwrap(),
+            commands: Vec::new(),
+            controller_subsystem: sdl_context.game_controller().ok(),
+            controllers: Vec::new(),
+            key_hints: KeyHints::default(),
+        }
     }
 
+    /// Lets the arrow keys, the d-pad and the A and B buttons press the
+    /// keypad keys a ROM uses for them.
+    pub fn set_key_hints(&mut self, key_hints: KeyHints) {
+        self.key_hints = key_hints;
+    }
+
+    /// Commands received since the last call.
+    pub fn commands(&mut self) -> Vec<Command> {
+        self.commands.drain(..).collect()
+    }
 
     pub fn poll(&mut self) -> Result<[bool; 16],()> {
 
         for event in self.events.poll_iter() {
-            if let Event::Quit { .. } = event {
-                return Err(());
+            let command = match event {
+                Event::Quit { .. } => return Err(()),
+                // Menu navigation auto-repeats while a key is held
+                Event::KeyDown { keycode: Some(key), .. } if menu_input(key).is_some() => menu_input(key).map(Command::Menu),
+                Event::KeyDown { keycode: Some(key), keymod, repeat: false, .. } => match key {
+                    Keycode::F11 => Some(Command::ToggleFullscreen),
+                    Keycode::F10 => Some(Command::ToggleIntegerScaling),
+                    Keycode::F9 => Some(Command::ToggleEffects),
+                    Keycode::F8 => Some(Command::NextPalette),
+                    Keycode::F7 => Some(Command::ToggleRecording),
+                    Keycode::F12 => Some(Command::Screenshot {
+                        native: keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD),
+                    }),
+                    Keycode::F5 => Some(Command::Reset {
+                        hard: keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD),
+                    }),
+                    Keycode::Escape => Some(Command::ToggleMenu),
+                    Keycode::F6 => Some(Command::ToggleCounter),
+                    Keycode::Tab => Some(Command::ToggleTurbo),
+                    Keycode::Backquote => Some(Command::SlowMotion),
+                    Keycode::P => Some(Command::TogglePause),
+                    Keycode::Period => Some(Command::FrameAdvance),
+                    Keycode::Comma => Some(Command::Step),
+                    Keycode::F2 => Some(Command::ToggleDebugger),
+                    Keycode::Equals | Keycode::KpPlus => Some(Command::ScaleUp),
+                    Keycode::Minus | Keycode::KpMinus => Some(Command::ScaleDown),
+                    _ => None,
+                },
+                Event::Window { win_event: WindowEvent::Resized(..), .. }
+                | Event::Window { win_event: WindowEvent::SizeChanged(..), .. }
+                | Event::Window { win_event: WindowEvent::Exposed, .. } => Some(Command::Redraw),
+                Event::ControllerDeviceAdded { which, .. } => {
+                    if let Some(Ok(controller)) = self.controller_subsystem.as_ref().map(|s| s.open(which)) {
+                        self.controllers.push(controller);
+                    }
+                    None
+                }
+                Event::ControllerButtonDown { button, .. } => match button {
+                    Button::Start => Some(Command::ToggleMenu),
+                    Button::DPadUp => Some(Command::Menu(MenuInput::Up)),
+                    Button::DPadDown => Some(Command::Menu(MenuInput::Down)),
+                    Button::DPadLeft => Some(Command::Menu(MenuInput::Left)),
+                    Button::DPadRight => Some(Command::Menu(MenuInput::Right)),
+                    Button::A => Some(Command::Menu(MenuInput::Select)),
+                    Button::B => Some(Command::Menu(MenuInput::Back)),
+                    _ => None,
+                },
+                _ => None,
             };
+            if let Some(command) = command {
+                self.commands.push(command);
+            }
         }
 
         let keys: Vec<Keycode> = self.events
@@ -28,7 +139,7 @@ impl Input {
 
         let mut chip8_keys = [false; 16];
 
-        for key in keys {
+        for &key in keys.iter() {
             let index = match key {
                 Keycode::Num1 => Some(0x1),
                 Keycode::Num2 => Some(0x2),
@@ -54,6 +165,33 @@ impl Input {
             }
         }
 
+        let hints = [
+            (Keycode::Up, Button::DPadUp, self.key_hints.up),
+            (Keycode::Down, Button::DPadDown, self.key_hints.down),
+            (Keycode::Left, Button::DPadLeft, self.key_hints.left),
+            (Keycode::Right, Button::DPadRight, self.key_hints.right),
+            (Keycode::RCtrl, Button::A, self.key_hints.a),
+            (Keycode::RShift, Button::B, self.key_hints.b),
+        ];
+        for (key, button, hint) in hints {
+            let pressed = keys.contains(&key) || self.controllers.iter().any(|c| c.button(button));
+            if let (true, Some(i)) = (pressed, hint) {
+                chip8_keys[i as usize] = true;
+            }
+        }
+
         Ok(chip8_keys)
     }
 }
+
+fn menu_input(key: Keycode) -> Option<MenuInput> {
+    match key {
+        Keycode::Up => Some(MenuInput::Up),
+        Keycode::Down => Some(MenuInput::Down),
+        Keycode::Left => Some(MenuInput::Left),
+        Keycode::Right => Some(MenuInput::Right),
+        Keycode::Return | Keycode::KpEnter => Some(MenuInput::Select),
+        Keycode::Backspace => Some(MenuInput::Back),
+        _ => None,
+    }
+}
@@ -0,0 +1,132 @@
+// Items shown at once, longer menus scroll
+const VISIBLE_ITEMS: usize = 12;
+
+/// Navigation of a menu, from the keyboard or a gamepad.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MenuInput {
+    Up,
+    Down,
+    // Change the value of the selected choice
+    Left,
+    Right,
+    Select,
+    Back,
+}
+
+/// What happened to a menu after an input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MenuEvent<T> {
+    /// An item was selected
+    Selected(T),
+    /// A choice was changed to the value at this index
+    Changed(T, usize),
+    /// The user backed out of the menu
+    Closed,
+}
+
+/// Menu as drawn by the OSD: a title and the visible lines, one of them
+/// highlighted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Panel {
+    pub title: String,
+    pub lines: Vec<String>,
+    pub selected: Option<usize>,
+}
+
+struct Item<T> {
+    id: T,
+    label: String,
+    // Values to pick from with left and right, empty for plain actions
+    choices: Vec<String>,
+    choice: usize,
+}
+
+/// A list of actions and choices navigated with `MenuInput`s, identifying
+/// its items to the caller with ids of type `T`.
+pub struct Menu<T> {
+    title: String,
+    items: Vec<Item<T>>,
+    selected: usize,
+    // First visible item
+    scroll: usize,
+}
+
+impl<T: Copy> Menu<T> {
+    pub fn new(title: &str) -> Self {
+        Menu { title: String::from(title), items: Vec::new(), selected: 0, scroll: 0 }
+    }
+
+    pub fn action(mut self, id: T, label: &str) -> Self {
+        self.items.push(Item { id, label: String::from(label), choices: Vec::new(), choice: 0 });
+        self
+    }
+
+    /// An item cycling through `choices`, starting at index `current`.
+    pub fn choice(mut self, id: T, label: &str, choices: Vec<String>, current: usize) -> Self {
+        let choice = current.min(choices.len().saturating_sub(1));
+        self.items.push(Item { id, label: String::from(label), choices, choice });
+        self
+    }
+
+    pub fn input(&mut self, input: MenuInput) -> Option<MenuEvent<T>> {
+        if self.items.is_empty() {
+            return match input {
+                MenuInput::Back => Some(MenuEvent::Closed),
+                _ => None,
+            };
+        }
+        match input {
+            MenuInput::Up => self.select((self.selected + self.items.len() - 1) % self.items.len()),
+            MenuInput::Down => self.select((self.selected + 1) % self.items.len()),
+            MenuInput::Left | MenuInput::Right => {
+                let item = &mut self.items[self.selected];
+                if item.choices.is_empty() {
+                    return None;
+                }
+                let count = item.choices.len();
+                item.choice = match input {
+                    MenuInput::Left => (item.choice + count - 1) % count,
+                    _ => (item.choice + 1) % count,
+                };
+                return Some(MenuEvent::Changed(item.id, item.choice));
+            }
+            MenuInput::Select => return Some(MenuEvent::Selected(self.items[self.selected].id)),
+            MenuInput::Back => return Some(MenuEvent::Closed),
+        }
+        None
+    }
+
+    /// Current value of the choice with this id.
+    pub fn choice_of(&self, id: T) -> Option<usize>
+    where
+        T: PartialEq,
+    {
+        self.items.iter().find(|item| item.id == id).map(|item| item.choice)
+    }
+
+    pub fn panel(&self) -> Panel {
+        let width = self.items.iter().map(|item| item.label.len()).max().unwrap_or(0);
+        let lines = self.items.iter()
+            .skip(self.scroll)
+            .take(VISIBLE_ITEMS)
+            .map(|item| match item.choices.get(item.choice) {
+                Some(value) => format!("{:width$}  < {} >", item.label, value, width = width),
+                None => item.label.clone(),
+            })
+            .collect();
+        Panel {
+            title: self.title.clone(),
+            lines,
+            selected: self.selected.checked_sub(self.scroll),
+        }
+    }
+
+    fn select(&mut self, index: usize) {
+        self.selected = index;
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + VISIBLE_ITEMS {
+            self.scroll = self.selected + 1 - VISIBLE_ITEMS;
+        }
+    }
+}
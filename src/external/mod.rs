@@ -1,7 +1,27 @@
 mod rom;
+mod debugger;
+mod effects;
+mod menu;
+mod osd;
+mod speed;
+#[cfg(any(feature = "sdl", test))]
+mod viewport;
+#[cfg(feature = "sdl")]
 mod graphics;
+#[cfg(feature = "sdl")]
 mod input;
+#[cfg(feature = "tui")]
+mod terminal;
 
-pub use rom::load_rom;
-pub use graphics::Screen;
-pub use input::Input;
\ No newline at end of file
+pub use rom::{load_rom, prompt_rom, Rom};
+pub use debugger::{render_debugger, DEBUGGER_HEIGHT, DEBUGGER_WIDTH};
+pub use effects::Effects;
+pub use menu::{Menu, MenuEvent, MenuInput, Panel};
+pub use osd::{Osd, OSD_HEIGHT, OSD_WIDTH};
+pub use speed::{Pacing, Speed, SpeedMeter};
+#[cfg(feature = "sdl")]
+pub use graphics::{Persistence, Screen};
+#[cfg(feature = "sdl")]
+pub use input::{Command, Input};
+#[cfg(feature = "tui")]
+pub use terminal::Terminal;
@@ -0,0 +1,241 @@
+use std::time::{Duration, Instant};
+
+use crate::cpu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use super::menu::Panel;
+
+// Overlay pixels per emulated pixel, so text is finer than the game picture
+const OSD_SCALE: usize = 4;
+pub const OSD_WIDTH: usize = SCREEN_WIDTH * OSD_SCALE;
+pub const OSD_HEIGHT: usize = SCREEN_HEIGHT * OSD_SCALE;
+
+const GLYPH_WIDTH: usize = 3;
+pub(super) const GLYPH_HEIGHT: usize = 5;
+pub(super) const ADVANCE: usize = GLYPH_WIDTH + 1;
+pub(super) const LINE_HEIGHT: usize = GLYPH_HEIGHT + 3;
+// Space between the text and the edges of the overlay
+pub(super) const MARGIN: usize = 2;
+
+const NOTIFICATION_DURATION: Duration = Duration::from_secs(2);
+// Older notifications are dropped when more are shown at once
+const MAX_NOTIFICATIONS: usize = 4;
+
+pub(super) const TEXT_COLOR: [u8; 4] = [255, 255, 255, 255];
+const BACKGROUND_COLOR: [u8; 4] = [0, 0, 0, 176];
+const PANEL_COLOR: [u8; 4] = [0, 0, 0, 224];
+pub(super) const HIGHLIGHT_COLOR: [u8; 4] = [0, 0, 0, 255];
+
+// 3x5 glyphs for ' ' to '_', one row per byte with the leftmost pixel in
+// bit 2. Lowercase letters are drawn in uppercase.
+const FONT: [[u8; GLYPH_HEIGHT]; 64] = [
+    [0b000, 0b000, 0b000, 0b000, 0b000], // ' '
+    [0b010, 0b010, 0b010, 0b000, 0b010], // !
+    [0b101, 0b101, 0b000, 0b000, 0b000], // "
+    [0b101, 0b111, 0b101, 0b111, 0b101], // #
+    [0b011, 0b110, 0b010, 0b011, 0b110], // $
+    [0b101, 0b001, 0b010, 0b100, 0b101], // %
+    [0b010, 0b101, 0b010, 0b101, 0b011], // &
+    [0b010, 0b010, 0b000, 0b000, 0b000], // '
+    [0b001, 0b010, 0b010, 0b010, 0b001], // (
+    [0b100, 0b010, 0b010, 0b010, 0b100], // )
+    [0b000, 0b101, 0b010, 0b101, 0b000], // *
+    [0b000, 0b010, 0b111, 0b010, 0b000], // +
+    [0b000, 0b000, 0b000, 0b010, 0b100], // ,
+    [0b000, 0b000, 0b111, 0b000, 0b000], // -
+    [0b000, 0b000, 0b000, 0b000, 0b010], // .
+    [0b001, 0b001, 0b010, 0b100, 0b100], // /
+    [0b111, 0b101, 0b101, 0b101, 0b111], // 0
+    [0b010, 0b110, 0b010, 0b010, 0b111], // 1
+    [0b111, 0b001, 0b111, 0b100, 0b111], // 2
+    [0b111, 0b001, 0b111, 0b001, 0b111], // 3
+    [0b101, 0b101, 0b111, 0b001, 0b001], // 4
+    [0b111, 0b100, 0b111, 0b001, 0b111], // 5
+    [0b111, 0b100, 0b111, 0b101, 0b111], // 6
+    [0b111, 0b001, 0b001, 0b010, 0b010], // 7
+    [0b111, 0b101, 0b111, 0b101, 0b111], // 8
+    [0b111, 0b101, 0b111, 0b001, 0b111], // 9
+    [0b000, 0b010, 0b000, 0b010, 0b000], // :
+    [0b000, 0b010, 0b000, 0b010, 0b100], // ;
+    [0b001, 0b010, 0b100, 0b010, 0b001], // <
+    [0b000, 0b111, 0b000, 0b111, 0b000], // =
+    [0b100, 0b010, 0b001, 0b010, 0b100], // >
+    [0b111, 0b001, 0b011, 0b000, 0b010], // ?
+    [0b010, 0b101, 0b111, 0b100, 0b011], // @
+    [0b010, 0b101, 0b111, 0b101, 0b101], // A
+    [0b110, 0b101, 0b110, 0b101, 0b110], // B
+    [0b011, 0b100, 0b100, 0b100, 0b011], // C
+    [0b110, 0b101, 0b101, 0b101, 0b110], // D
+    [0b111, 0b100, 0b110, 0b100, 0b111], // E
+    [0b111, 0b100, 0b110, 0b100, 0b100], // F
+    [0b011, 0b100, 0b101, 0b101, 0b011], // G
+    [0b101, 0b101, 0b111, 0b101, 0b101], // H
+    [0b111, 0b010, 0b010, 0b010, 0b111], // I
+    [0b001, 0b001, 0b001, 0b101, 0b010], // J
+    [0b101, 0b101, 0b110, 0b101, 0b101], // K
+    [0b100, 0b100, 0b100, 0b100, 0b111], // L
+    [0b101, 0b111, 0b111, 0b101, 0b101], // M
+    [0b110, 0b101, 0b101, 0b101, 0b101], // N
+    [0b010, 0b101, 0b101, 0b101, 0b010], // O
+    [0b110, 0b101, 0b110, 0b100, 0b100], // P
+    [0b010, 0b101, 0b101, 0b110, 0b011], // Q
+    [0b110, 0b101, 0b110, 0b101, 0b101], // R
+    [0b011, 0b100, 0b010, 0b001, 0b110], // S
+    [0b111, 0b010, 0b010, 0b010, 0b010], // T
+    [0b101, 0b101, 0b101, 0b101, 0b111], // U
+    [0b101, 0b101, 0b101, 0b101, 0b010], // V
+    [0b101, 0b101, 0b111, 0b111, 0b101], // W
+    [0b101, 0b101, 0b010, 0b101, 0b101], // X
+    [0b101, 0b101, 0b010, 0b010, 0b010], // Y
+    [0b111, 0b001, 0b010, 0b100, 0b111], // Z
+    [0b011, 0b010, 0b010, 0b010, 0b011], // [
+    [0b100, 0b100, 0b010, 0b001, 0b001], // \
+    [0b110, 0b010, 0b010, 0b010, 0b110], // ]
+    [0b010, 0b101, 0b000, 0b000, 0b000], // ^
+    [0b000, 0b000, 0b000, 0b000, 0b111], // _
+];
+
+/// Text shown over the game picture: short-lived notifications stacked in
+/// the bottom left corner, an optional status line in the top right, e.g. a
+/// frame counter, and a menu panel in the middle. Rendered with a built-in
+/// 3x5 pixel font.
+pub struct Osd {
+    notifications: Vec<(String, Instant)>,
+    status: Option<String>,
+    panel: Option<Panel>,
+    // Whether the text changed since the overlay was last rendered
+    changed: bool,
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Osd { notifications: Vec::new(), status: None, panel: None, changed: false }
+    }
+
+    /// Shows `text` for a couple of seconds.
+    pub fn notify(&mut self, text: &str) {
+        if self.notifications.len() == MAX_NOTIFICATIONS {
+            self.notifications.remove(0);
+        }
+        self.notifications.push((String::from(text), Instant::now()));
+        self.changed = true;
+    }
+
+    /// Replaces the status line, `None` hiding it.
+    pub fn set_status(&mut self, status: Option<&str>) {
+        if self.status.as_deref() != status {
+            self.status = status.map(String::from);
+            self.changed = true;
+        }
+    }
+
+    /// Shows a menu in the middle of the screen, `None` hiding it.
+    pub fn set_panel(&mut self, panel: Option<Panel>) {
+        if self.panel != panel {
+            self.panel = panel;
+            self.changed = true;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notifications.is_empty() && self.status.is_none() && self.panel.is_none()
+    }
+
+    /// Drops expired notifications. Returns whether the overlay has to be
+    /// rendered again.
+    pub fn update(&mut self) -> bool {
+        let count = self.notifications.len();
+        self.notifications.retain(|(_, shown)| shown.elapsed() < NOTIFICATION_DURATION);
+        let changed = self.changed || self.notifications.len() != count;
+        self.changed = false;
+        changed
+    }
+
+    /// Renders the text into `rgba`, an `OSD_WIDTH` x `OSD_HEIGHT` RGBA
+    /// picture which is transparent everywhere else.
+    pub fn render(&self, rgba: &mut [u8]) {
+        rgba.iter_mut().for_each(|b| *b = 0);
+
+        if let Some(ref status) = self.status {
+            let x = OSD_WIDTH.saturating_sub(text_width(status) + MARGIN);
+            draw_text(rgba, OSD_WIDTH, status, x, MARGIN, TEXT_COLOR, BACKGROUND_COLOR);
+        }
+
+        if let Some(ref panel) = self.panel {
+            draw_panel(rgba, panel);
+        }
+
+        let count = self.notifications.len();
+        for (i, (text, _)) in self.notifications.iter().enumerate() {
+            let y = OSD_HEIGHT - MARGIN - GLYPH_HEIGHT - (count - 1 - i) * LINE_HEIGHT;
+            draw_text(rgba, OSD_WIDTH, text, MARGIN, y, TEXT_COLOR, BACKGROUND_COLOR);
+        }
+    }
+}
+
+impl Default for Osd {
+    fn default() -> Self {
+        Osd::new()
+    }
+}
+
+pub(super) fn text_width(text: &str) -> usize {
+    (text.chars().count() * ADVANCE).saturating_sub(1)
+}
+
+fn glyph(c: char) -> &'static [u8; GLYPH_HEIGHT] {
+    let c = c.to_ascii_uppercase();
+    match c {
+        ' '..='_' => &FONT[c as usize - ' ' as usize],
+        _ => &FONT['?' as usize - ' ' as usize],
+    }
+}
+
+// Centered box with the title, an empty line and the items, the selected
+// one in inverted colors
+fn draw_panel(rgba: &mut [u8], panel: &Panel) {
+    let width = panel.lines.iter().chain(Some(&panel.title)).map(|line| text_width(line)).max().unwrap_or(0);
+    let height = (panel.lines.len() + 2) * LINE_HEIGHT;
+    let x = OSD_WIDTH.saturating_sub(width) / 2;
+    let y = OSD_HEIGHT.saturating_sub(height) / 2;
+    fill(rgba, OSD_WIDTH, x.saturating_sub(MARGIN + 1), y.saturating_sub(MARGIN + 1), width + 2 * MARGIN + 2, height + 2 * MARGIN, PANEL_COLOR);
+
+    draw_text(rgba, OSD_WIDTH, &panel.title, x, y, TEXT_COLOR, PANEL_COLOR);
+    for (i, line) in panel.lines.iter().enumerate() {
+        let line_y = y + (i + 2) * LINE_HEIGHT;
+        if panel.selected == Some(i) {
+            fill(rgba, OSD_WIDTH, x - 1, line_y - 1, width + 2, GLYPH_HEIGHT + 2, TEXT_COLOR);
+            draw_text(rgba, OSD_WIDTH, line, x, line_y, HIGHLIGHT_COLOR, TEXT_COLOR);
+        } else {
+            draw_text(rgba, OSD_WIDTH, line, x, line_y, TEXT_COLOR, PANEL_COLOR);
+        }
+    }
+}
+
+// Draws `text` with its top left corner at (`x`, `y`) on a box of the
+// `background` color, clipping whatever falls outside of the picture, `stride`
+// pixels wide
+pub(super) fn draw_text(rgba: &mut [u8], stride: usize, text: &str, x: usize, y: usize, color: [u8; 4], background: [u8; 4]) {
+    let box_x = x.saturating_sub(1);
+    let box_y = y.saturating_sub(1);
+    fill(rgba, stride, box_x, box_y, text_width(text) + 2, GLYPH_HEIGHT + 2, background);
+
+    for (i, c) in text.chars().enumerate() {
+        let glyph_x = x + i * ADVANCE;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (0b100 >> column) != 0 {
+                    fill(rgba, stride, glyph_x + column, y + row, 1, 1, color);
+                }
+            }
+        }
+    }
+}
+
+pub(super) fn fill(rgba: &mut [u8], stride: usize, x: usize, y: usize, width: usize, height: usize, color: [u8; 4]) {
+    let rows = rgba.len() / (stride * 4);
+    for py in y..(y + height).min(rows) {
+        for px in x..(x + width).min(stride) {
+            let offset = (py * stride + px) * 4;
+            rgba[offset..offset + 4].copy_from_slice(&color);
+        }
+    }
+}
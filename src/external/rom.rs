@@ -1,25 +1,278 @@
-use std::io::Read;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Cursor, Read, Write};
+use std::path::Path;
 
-fn load_rom_file(path: &str) -> File {
-    return match File::open(path) {
-        Err(why) => panic!("Couldn't open {}: {}", path, why),
-        Ok(f) => f, 
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use crate::cartridge::{self, Cartridge};
+use crate::cpu::MAX_ROM_SIZE;
+use crate::database::{Database, Metadata};
+use crate::octo;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZIP_MAGIC: [u8; 4] = [b'P', b'K', 0x03, 0x04];
+// Empty archives only have the end of the central directory
+const EMPTY_ZIP_MAGIC: [u8; 4] = [b'P', b'K', 0x05, 0x06];
+// Files of an archive taken for ROMs, anything else is ignored unless the
+// archive holds nothing with these extensions
+const ROM_EXTENSIONS: [&str; 5] = ["ch8", "c8", "sc8", "xo8", "bin"];
+// Archives and cartridges are larger than the ROMs they hold, but reading
+// them stops somewhere too so that `/dev/zero` or an endless pipe can't
+// fill the host's memory
+const MAX_FILE_SIZE: u64 = 16 << 20;
+
+/// A ROM's bytes and what the database knows about it.
+pub struct Rom {
+    pub bytes: Vec<u8>,
+    pub metadata: Option<Metadata>,
+}
+
+/// Reads a ROM from a file, a gzip-compressed file, a zip archive or a
+/// directory, or from stdin when `path` is `-`. When an archive or a
+/// directory holds several ROMs `choose` is given their names and picks
+/// one, returning `None` to give up. Octo cartridges are assembled, and
+/// run with the settings they embed.
+pub fn load_rom<F>(path: &str, database: &Database, choose: F) -> Result<Rom, String>
+where
+    F: FnOnce(&[String]) -> Option<usize>,
+{
+    if Path::new(path).is_dir() {
+        let bytes = from_directory(Path::new(path), choose).map_err(|why| format!("Couldn't load {}: {}", path, why))?;
+        let metadata = database.lookup(&bytes).cloned();
+        return Ok(Rom { bytes, metadata });
+    }
+
+    let mut bytes = Vec::new();
+    let read = if path == "-" {
+        io::stdin().take(MAX_FILE_SIZE + 1).read_to_end(&mut bytes)
+    } else {
+        File::open(path)
+            .map_err(|why| format!("Couldn't open {}: {}", path, why))?
+            .take(MAX_FILE_SIZE + 1)
+            .read_to_end(&mut bytes)
+    };
+    read.map_err(|why| format!("Couldn't read {}: {}", path, why))?;
+    if bytes.len() as u64 > MAX_FILE_SIZE {
+        return Err(format!("Couldn't load {}: ROM does not fit in memory", path));
+    }
+
+    if cartridge::is_cartridge(&bytes) {
+        return from_cartridge(&bytes, database).map_err(|why| format!("Couldn't load {}: {}", path, why));
+    }
+    let bytes = unpack(bytes, choose).map_err(|why| format!("Couldn't load {}: {}", path, why))?;
+    let metadata = database.lookup(&bytes).cloned();
+    Ok(Rom { bytes, metadata })
+}
+
+/// Asks on the terminal which of `names` to load, for `load_rom`.
+pub fn prompt_rom(names: &[String]) -> Option<usize> {
+    let mut stderr = io::stderr();
+    for (i, name) in names.iter().enumerate() {
+        writeln!(stderr, "{:>3}) {}", i + 1, name).ok()?;
+    }
+    write!(stderr, "Which ROM? ").ok()?;
+    stderr.flush().ok()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer).ok()?;
+    match answer.trim().parse::<usize>() {
+        Ok(n) if n >= 1 && n <= names.len() => Some(n - 1),
+        _ => None,
+    }
+}
+
+// Archives are recognised by their contents rather than their extension,
+// so ROMs read from stdin can be compressed as well
+fn unpack<F>(bytes: Vec<u8>, choose: F) -> Result<Vec<u8>, String>
+where
+    F: FnOnce(&[String]) -> Option<usize>,
+{
+    if bytes.starts_with(&GZIP_MAGIC) {
+        read_rom(GzDecoder::new(&bytes[..]))
+    } else if bytes.starts_with(&ZIP_MAGIC) || bytes.starts_with(&EMPTY_ZIP_MAGIC) {
+        unzip(bytes, choose)
+    } else {
+        read_rom(&bytes[..])
+    }
+}
+
+// The cartridge's settings are those its author wrote the game with, they
+// take precedence over the database's
+fn from_cartridge(bytes: &[u8], database: &Database) -> Result<Rom, String> {
+    let cartridge = Cartridge::decode(bytes)?;
+    let bytes = octo::assemble(&cartridge.program)?;
+    let embedded = cartridge.metadata;
+    let metadata = match database.lookup(&bytes).cloned() {
+        Some(known) => Metadata {
+            quirks: embedded.quirks.or(known.quirks),
+            tickrate: embedded.tickrate.or(known.tickrate),
+            palette: embedded.palette.or(known.palette),
+            ..known
+        },
+        None => embedded,
     };
+    Ok(Rom { bytes, metadata: Some(metadata) })
+}
+
+fn unzip<F>(bytes: Vec<u8>, choose: F) -> Result<Vec<u8>, String>
+where
+    F: FnOnce(&[String]) -> Option<usize>,
+{
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).map_err(|why| why.to_string())?;
+    let files: Vec<String> = archive.file_names()
+        .filter(|name| !name.ends_with('/') && !name.starts_with("__MACOSX/"))
+        .map(String::from)
+        .collect();
+    let roms: Vec<String> = files.iter().filter(|name| is_rom(name)).cloned().collect();
+    let names = if roms.is_empty() { files } else { roms };
+
+    let name = pick(names, choose, "the archive")?;
+    let file = archive.by_name(&name).map_err(|why| why.to_string())?;
+    read_rom(file).map_err(|why| format!("{}: {}", name, why))
+}
+
+// Only files with a ROM extension are taken from directories
+fn from_directory<F>(dir: &Path, choose: F) -> Result<Vec<u8>, String>
+where
+    F: FnOnce(&[String]) -> Option<usize>,
+{
+    let names: Vec<String> = fs::read_dir(dir)
+        .map_err(|why| why.to_string())?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| is_rom(name))
+        .collect();
+
+    let name = pick(names, choose, "the directory")?;
+    let file = File::open(dir.join(&name)).map_err(|why| format!("{}: {}", name, why))?;
+    read_rom(file).map_err(|why| format!("{}: {}", name, why))
 }
 
-fn load_rom_bytes_from_file(file: &mut File) -> Vec<u8> {
-    let mut vec: Vec<u8> = Vec::new();
-    return match file.read_to_end(&mut vec) {
-        Err(why) => panic!("Couldn't read byte stream: {}", why),
-        Ok(size) => {
-            println!("Read {} bytes of data", size);
-            vec
+// The only name, or the one `choose` picks among the sorted names
+fn pick<F>(mut names: Vec<String>, choose: F, container: &str) -> Result<String, String>
+where
+    F: FnOnce(&[String]) -> Option<usize>,
+{
+    names.sort();
+    match names.len() {
+        0 => Err(format!("{} holds no ROMs", container)),
+        1 => Ok(names.remove(0)),
+        _ => match choose(&names) {
+            Some(i) if i < names.len() => Ok(names.remove(i)),
+            _ => Err(format!("{} holds several ROMs: {}", container, names.join(", "))),
         },
     }
 }
 
-pub fn load_rom(path: &str) -> Vec<u8> {
-    let mut file = load_rom_file(path);
-    load_rom_bytes_from_file(&mut file)
-}
\ No newline at end of file
+fn read_rom<R: Read>(reader: R) -> Result<Vec<u8>, String> {
+    let mut rom = Vec::new();
+    reader.take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut rom).map_err(|why| why.to_string())?;
+    if rom.len() > MAX_ROM_SIZE {
+        return Err(String::from("ROM does not fit in memory"));
+    }
+    Ok(rom)
+}
+
+fn is_rom(name: &str) -> bool {
+    Path::new(name).extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| ROM_EXTENSIONS.iter().any(|r| e.eq_ignore_ascii_case(r)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use zip::write::{SimpleFileOptions, ZipWriter};
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            writer.start_file(*name, SimpleFileOptions::default()).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn raw_and_gzipped_roms_are_read_as_is() {
+        assert_eq!(unpack(vec![0x12, 0x00], |_| None).unwrap(), [0x12, 0x00]);
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0x60, 0x01, 0x12, 0x00]).unwrap();
+        assert_eq!(unpack(encoder.finish().unwrap(), |_| None).unwrap(), [0x60, 0x01, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn the_single_rom_of_a_zip_is_taken() {
+        let archive = zip(&[("readme.txt", b"Have fun"), ("games/pong.ch8", &[0x12, 0x00])]);
+        assert_eq!(unpack(archive, |_| panic!("nothing to choose")).unwrap(), [0x12, 0x00]);
+    }
+
+    #[test]
+    fn one_of_several_roms_is_chosen() {
+        let archive = zip(&[("b.ch8", &[0x0B]), ("a.ch8", &[0x0A])]);
+        let mut offered = Vec::new();
+        let rom = unpack(archive.clone(), |names| {
+            offered = names.to_vec();
+            Some(1)
+        });
+        assert_eq!(rom.unwrap(), [0x0B]);
+        assert_eq!(offered, ["a.ch8", "b.ch8"]);
+
+        let error = unpack(archive, |_| None).unwrap_err();
+        assert!(error.contains("a.ch8, b.ch8"), "{}", error);
+        assert!(unpack(zip(&[]), |_| None).is_err());
+    }
+
+    #[test]
+    fn oversized_roms_are_not_decompressed() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&[0; 1 << 20]).unwrap();
+        let bomb = encoder.finish().unwrap();
+        assert_eq!(unpack(bomb, |_| None).unwrap_err(), "ROM does not fit in memory");
+
+        let error = unpack(zip(&[("big.ch8", &[0; 4096])]), |_| None).unwrap_err();
+        assert_eq!(error, "big.ch8: ROM does not fit in memory");
+        let error = unpack(zip(&[("big.ch8", &[0; MAX_ROM_SIZE + 1])]), |_| None).unwrap_err();
+        assert_eq!(error, "big.ch8: ROM does not fit in memory");
+        assert_eq!(unpack(zip(&[("full.ch8", &[0; MAX_ROM_SIZE])]), |_| None).unwrap().len(), MAX_ROM_SIZE);
+        assert_eq!(unpack(vec![0; MAX_ROM_SIZE + 1], |_| None).unwrap_err(), "ROM does not fit in memory");
+    }
+
+    #[test]
+    fn roms_are_picked_from_directories() {
+        let dir = std::env::temp_dir().join(format!("chip8-roms-{}", std::process::id()));
+        fs::create_dir_all(dir.join("nested.ch8")).unwrap();
+        fs::write(dir.join("notes.txt"), b"Have fun").unwrap();
+        fs::write(dir.join("pong.ch8"), [0x12, 0x00]).unwrap();
+        let path = dir.to_string_lossy();
+
+        let rom = load_rom(&path, &Database::default(), |_| panic!("nothing to choose")).unwrap();
+        assert_eq!(rom.bytes, [0x12, 0x00]);
+
+        fs::write(dir.join("tetris.ch8"), [0x13, 0x00]).unwrap();
+        let rom = load_rom(&path, &Database::default(), |names| names.iter().position(|n| n == "tetris.ch8")).unwrap();
+        assert_eq!(rom.bytes, [0x13, 0x00]);
+        let error = load_rom(&path, &Database::default(), |_| None).err().unwrap();
+        assert!(error.ends_with("the directory holds several ROMs: pong.ch8, tetris.ch8"), "{}", error);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cartridges_are_assembled_with_their_settings() {
+        let json = r##"{"program": ": main\n  v0 := 1\n  loop again", "options": {"tickrate": 20}}"##;
+        let rom = from_cartridge(&cartridge::tests::cartridge(json), &Database::default()).unwrap();
+        assert_eq!(rom.bytes, [0x60, 0x01, 0x12, 0x02]);
+        assert_eq!(rom.metadata.unwrap().tickrate, Some(20));
+
+        let json = r##"{"program": ": main\n  hires", "options": {}}"##;
+        let error = from_cartridge(&cartridge::tests::cartridge(json), &Database::default()).err().unwrap();
+        assert_eq!(error, "line 2: hires needs SCHIP or XO-CHIP, which this emulator doesn't run");
+    }
+}
@@ -0,0 +1,164 @@
+use std::time::{Duration, Instant};
+
+// Slow motion speeds cycled through, in real frames per emulated frame
+const SLOW_MOTION: [u32; 2] = [2, 4];
+// How often the measured speed is updated
+const MEASURE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How fast emulated 60 Hz frames run compared to real time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    Normal,
+    /// As many frames as the host can run, only some of them presented
+    Turbo,
+    /// One emulated frame every `n` real frames
+    Slow(u32),
+}
+
+/// Decides how many emulated frames to run in each real 60 Hz frame,
+/// following the speed, pause and frame advance hotkeys.
+pub struct Pacing {
+    speed: Speed,
+    paused: bool,
+    // Frames requested by frame advance while paused
+    advance: u32,
+    // Real frames since the last emulated frame in slow motion
+    waited: u32,
+}
+
+impl Pacing {
+    pub fn new() -> Self {
+        Pacing { speed: Speed::Normal, paused: false, advance: 0, waited: 0 }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.waited = 0;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn toggle_turbo(&mut self) {
+        self.speed = if self.speed == Speed::Turbo { Speed::Normal } else { Speed::Turbo };
+    }
+
+    /// Goes from normal speed through each slow motion speed and back.
+    pub fn cycle_slow_motion(&mut self) {
+        let next = match self.speed {
+            Speed::Slow(n) => SLOW_MOTION.iter().position(|&s| s == n).map(|i| i + 1),
+            _ => Some(0),
+        };
+        self.speed = match next.and_then(|i| SLOW_MOTION.get(i)) {
+            Some(&n) => Speed::Slow(n),
+            None => Speed::Normal,
+        };
+        self.waited = 0;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.advance = 0;
+    }
+
+    /// Pauses, or runs a single frame when already paused.
+    pub fn advance(&mut self) {
+        if self.paused {
+            self.advance += 1;
+        } else {
+            self.paused = true;
+        }
+    }
+
+    /// Whether frames should be run back to back until the real frame is
+    /// over instead of `frames_due` of them.
+    pub fn is_unlimited(&self) -> bool {
+        !self.paused && self.speed == Speed::Turbo
+    }
+
+    /// Emulated frames to run in the current real frame, called once per
+    /// real frame.
+    pub fn frames_due(&mut self) -> u32 {
+        if self.paused {
+            return std::mem::take(&mut self.advance);
+        }
+        match self.speed {
+            Speed::Normal | Speed::Turbo => 1,
+            Speed::Slow(n) => {
+                self.waited += 1;
+                if self.waited >= n {
+                    self.waited = 0;
+                    1
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+impl Default for Pacing {
+    fn default() -> Self {
+        Pacing::new()
+    }
+}
+
+/// Measures emulated frames and instructions per second, and the speed as
+/// a percentage of 60 frames per second.
+pub struct SpeedMeter {
+    started: Instant,
+    frames: u32,
+    instructions: u64,
+    fps: f32,
+    ips: u64,
+}
+
+impl SpeedMeter {
+    pub fn new() -> Self {
+        SpeedMeter { started: Instant::now(), frames: 0, instructions: 0, fps: 60.0, ips: 0 }
+    }
+
+    /// Counts an emulated frame which ran `instructions` instructions.
+    pub fn frame(&mut self, instructions: u32) {
+        self.frames += 1;
+        self.instructions += instructions as u64;
+    }
+
+    /// Takes a new measurement about once a second, returning whether it
+    /// did.
+    pub fn update(&mut self) -> bool {
+        let elapsed = self.started.elapsed().as_secs_f32();
+        if elapsed < MEASURE_INTERVAL.as_secs_f32() {
+            return false;
+        }
+        self.fps = self.frames as f32 / elapsed;
+        self.ips = (self.instructions as f32 / elapsed).round() as u64;
+        self.started = Instant::now();
+        self.frames = 0;
+        self.instructions = 0;
+        true
+    }
+
+    pub fn fps(&self) -> f32 {
+        self.fps
+    }
+
+    pub fn ips(&self) -> u64 {
+        self.ips
+    }
+
+    pub fn percent(&self) -> u32 {
+        (self.fps / 60.0 * 100.0).round() as u32
+    }
+}
+
+impl Default for SpeedMeter {
+    fn default() -> Self {
+        SpeedMeter::new()
+    }
+}
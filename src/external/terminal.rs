@@ -0,0 +1,169 @@
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::style::Print;
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+
+use std::io::{self, BufWriter, Stdout, Write};
+use std::time::Duration;
+
+use crate::cpu::{DirtyRect, Machine, SCREEN_WIDTH};
+
+// Terminals only report key presses (and auto-repeats), so without keyboard
+// enhancement a key is considered held for this many frames after its last
+// press. Long enough to bridge the usual delay before auto-repeat starts.
+const HOLD_FRAMES: u32 = 30;
+
+// Column where the register panel starts, right of the picture
+const PANEL_COLUMN: u16 = SCREEN_WIDTH as u16 + 2;
+
+/// Frontend running in a terminal in raw mode: the screen is drawn with
+/// Unicode half blocks, two pixel rows per line, next to the registers.
+/// The terminal is restored when dropped.
+pub struct Terminal {
+    out: BufWriter<Stdout>,
+    // Whether the terminal reports key releases
+    enhanced: bool,
+    // Frames each keypad key is still held for
+    held: [u32; 16],
+}
+
+impl Terminal {
+    pub fn new() -> io::Result<Self> {
+        let mut out = BufWriter::new(io::stdout());
+        terminal::enable_raw_mode()?;
+        execute!(out, EnterAlternateScreen, Hide, Clear(ClearType::All))?;
+
+        let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if enhanced {
+            execute!(out, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        }
+
+        Ok(Terminal { out, enhanced, held: [0; 16] })
+    }
+
+    /// The keypad state, or `None` when the user asked to quit (Esc or
+    /// Ctrl+C) or the terminal can't be read anymore.
+    pub fn poll(&mut self) -> Option<[bool; 16]> {
+        if !self.enhanced {
+            for held in self.held.iter_mut() {
+                *held = held.saturating_sub(1);
+            }
+        }
+
+        while event::poll(Duration::from_secs(0)).unwrap_or(false) {
+            let key = match event::read() {
+                Ok(Event::Key(key)) => key,
+                Ok(_) => continue,
+                Err(_) => return None,
+            };
+
+            if is_quit(&key) {
+                return None;
+            }
+
+            if let KeyCode::Char(c) = key.code {
+                if let Some(index) = keypad_index(c.to_ascii_lowercase()) {
+                    self.held[index] = match (self.enhanced, key.kind) {
+                        (true, KeyEventKind::Release) => 0,
+                        (true, _) => u32::MAX,
+                        (false, _) => HOLD_FRAMES,
+                    };
+                }
+            }
+        }
+
+        let mut chip8_keys = [false; 16];
+        for (key, &held) in chip8_keys.iter_mut().zip(self.held.iter()) {
+            *key = held > 0;
+        }
+        Some(chip8_keys)
+    }
+
+    /// Redraws the lines covering `dirty` and the register panel.
+    pub fn draw(&mut self, machine: &Machine, dirty: Option<DirtyRect>) -> io::Result<()> {
+        let rows = match dirty {
+            Some(dirty) => dirty.y / 2..(dirty.y + dirty.height).div_ceil(2),
+            None => 0..0,
+        };
+        for row in rows {
+            let line: String = (0..SCREEN_WIDTH)
+                .map(|x| half_block(machine.screen[x][row * 2], machine.screen[x][row * 2 + 1]))
+                .collect();
+            queue!(self.out, MoveTo(0, row as u16), Print(line))?;
+        }
+
+        let mut panel = vec![
+            format!("PC {:#05x}", machine.pc),
+            format!("I  {:#05x}", machine.i),
+            format!("SP {:>5}", machine.stack.len()),
+            format!("DT {:>5}", machine.delay_timer),
+            format!("ST {:>5}", machine.sound_timer),
+            String::new(),
+        ];
+        for r in 0..8 {
+            panel.push(format!(
+                "V{:X} {:#04x}  V{:X} {:#04x}",
+                r, machine.registers[r], r + 8, machine.registers[r + 8]
+            ));
+        }
+        for (row, text) in panel.iter().enumerate() {
+            queue!(self.out, MoveTo(PANEL_COLUMN, row as u16), Print(text))?;
+        }
+
+        self.out.flush()
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if self.enhanced {
+            let _ = execute!(self.out, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(self.out, Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn is_quit(key: &KeyEvent) -> bool {
+    match key.code {
+        KeyCode::Esc => true,
+        KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+        _ => false,
+    }
+}
+
+fn half_block(top: bool, bottom: bool) -> char {
+    match (top, bottom) {
+        (true, true) => '█',
+        (true, false) => '▀',
+        (false, true) => '▄',
+        (false, false) => ' ',
+    }
+}
+
+// Same layout as the SDL frontend: the left side of a QWERTY keyboard
+fn keypad_index(key: char) -> Option<usize> {
+    match key {
+        '1' => Some(0x1),
+        '2' => Some(0x2),
+        '3' => Some(0x3),
+        '4' => Some(0xc),
+        'q' => Some(0x4),
+        'w' => Some(0x5),
+        'e' => Some(0x6),
+        'r' => Some(0xd),
+        'a' => Some(0x7),
+        's' => Some(0x8),
+        'd' => Some(0x9),
+        'f' => Some(0xe),
+        'z' => Some(0xa),
+        'x' => Some(0x0),
+        'c' => Some(0xb),
+        'v' => Some(0xf),
+        _ => None,
+    }
+}
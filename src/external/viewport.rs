@@ -0,0 +1,56 @@
+/// Largest rectangle with the aspect ratio of `image` that fits centered in
+/// `output`, the remaining area being left as black borders. Returned as
+/// `(x, y, width, height)`.
+pub(super) fn viewport(output: (u32, u32), image: (u32, u32), integer_scaling: bool) -> (i32, i32, u32, u32) {
+    let (output_width, output_height) = output;
+    let (image_width, image_height) = image;
+
+    let mut scale = (output_width as f32 / image_width as f32).min(output_height as f32 / image_height as f32);
+    if integer_scaling && scale >= 1.0 {
+        scale = scale.floor();
+    }
+
+    let width = ((image_width as f32 * scale) as u32).max(1);
+    let height = ((image_height as f32 * scale) as u32).max(1);
+    (
+        (output_width.saturating_sub(width) / 2) as i32,
+        (output_height.saturating_sub(height) / 2) as i32,
+        width,
+        height,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_picture_is_letterboxed() {
+        // Too tall a window gets borders above and below, too wide one on the sides
+        assert_eq!(viewport((1280, 1000), (64, 32), false), (0, 180, 1280, 640));
+        assert_eq!(viewport((2000, 640), (64, 32), false), (360, 0, 1280, 640));
+        assert_eq!(viewport((1280, 640), (64, 32), false), (0, 0, 1280, 640));
+    }
+
+    #[test]
+    fn the_aspect_ratio_is_kept_for_both_resolutions() {
+        for image in [(64, 32), (128, 64)] {
+            for output in [(1280, 640), (1000, 700), (333, 999), (1920, 1080)] {
+                let (_, _, width, height) = viewport(output, image, false);
+                assert!(width <= output.0 && height <= output.1);
+                assert!((width as f32 / height as f32 - 2.0).abs() < 0.01, "{:?} in {:?}: {}x{}", image, output, width, height);
+            }
+        }
+        assert_eq!(viewport((1280, 640), (128, 64), false), viewport((1280, 640), (64, 32), false));
+    }
+
+    #[test]
+    fn integer_scaling_uses_whole_multiples() {
+        // 15.6 pixels per emulated pixel round down to 15
+        assert_eq!(viewport((1000, 700), (64, 32), true), (20, 110, 960, 480));
+        assert_eq!(viewport((1000, 700), (128, 64), true), (52, 126, 896, 448));
+        assert_eq!(viewport((1000, 700), (64, 32), false), (0, 100, 1000, 500));
+        // Windows smaller than the picture still show all of it
+        assert_eq!(viewport((32, 16), (64, 32), true), (0, 0, 32, 16));
+    }
+}
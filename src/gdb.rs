@@ -0,0 +1,583 @@
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+use crate::cpu::{Chip8Machine, Machine};
+
+// Registers in the order of the target description: V0 to VF, I, PC and SP,
+// the number of nested calls
+const REGISTERS: usize = 19;
+const REGISTER_I: usize = 16;
+const REGISTER_PC: usize = 17;
+const REGISTER_SP: usize = 18;
+
+// Signals reported to the debugger when the machine stops
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// Replies waiting for a client that stopped reading, past which it is dropped
+const MAX_UNSENT: usize = 1 << 20;
+
+/// What changed in the debugger's connection during a poll.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GdbEvent {
+    Attached,
+    Detached,
+}
+
+/// A GDB remote serial protocol server on a localhost TCP port, letting gdb
+/// or an IDE inspect and control a `Machine`. It never blocks: the frontend
+/// polls it every frame, asks it before each instruction whether a
+/// breakpoint was hit and leaves the machine alone while it is halted.
+pub struct GdbStub {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    // Bytes received and not yet parsed into packets
+    received: Vec<u8>,
+    // Bytes the client's full receive buffer couldn't take yet
+    unsent: Vec<u8>,
+    breakpoints: BTreeSet<u16>,
+    halted: bool,
+    // Breakpoint the machine was resumed from, not hit again right away
+    resumed_at: Option<u16>,
+}
+
+impl GdbStub {
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbStub {
+            listener,
+            client: None,
+            received: Vec::new(),
+            unsent: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            halted: false,
+            resumed_at: None,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.listener.local_addr().map(|a| a.port()).unwrap_or(0)
+    }
+
+    /// Whether a debugger holds the machine stopped.
+    pub fn is_halted(&self) -> bool {
+        self.client.is_some() && self.halted
+    }
+
+    /// Accepts a debugger and answers its packets, without waiting for any.
+    /// The machine is stopped as soon as a debugger attaches.
+    pub fn poll(&mut self, machine: &mut Machine) -> Option<GdbEvent> {
+        let mut event = None;
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) if stream.set_nonblocking(true).is_ok() => {
+                    self.client = Some(stream);
+                    self.received.clear();
+                    self.unsent.clear();
+                    self.halted = true;
+                    event = Some(GdbEvent::Attached);
+                }
+                _ => return None,
+            }
+        }
+
+        self.flush();
+        let mut buffer = [0; 1024];
+        while let Some(client) = self.client.as_mut() {
+            match client.read(&mut buffer) {
+                Ok(0) => {
+                    self.detach();
+                    return Some(GdbEvent::Detached);
+                }
+                Ok(n) => self.received.extend_from_slice(&buffer[..n]),
+                Err(ref why) if why.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.detach();
+                    return Some(GdbEvent::Detached);
+                }
+            }
+        }
+
+        while let Some(packet) = self.next_packet() {
+            let reply = match packet {
+                Packet::Interrupt => {
+                    self.halted = true;
+                    Some(stop_reply(SIGINT))
+                }
+                Packet::Command(command) => self.handle(&command, machine),
+            };
+            if let Some(reply) = reply {
+                self.send(&reply);
+            }
+            if self.client.is_none() {
+                return Some(GdbEvent::Detached);
+            }
+        }
+        event
+    }
+
+    /// Called before each instruction while running. Returns whether the
+    /// machine reached a breakpoint, in which case it is now halted.
+    pub fn check_breakpoint(&mut self, machine: &Machine) -> bool {
+        if self.client.is_none() {
+            return false;
+        }
+        let pc = machine.pc as u16;
+        if self.resumed_at.take() == Some(pc) || !self.breakpoints.contains(&pc) {
+            return false;
+        }
+        self.halted = true;
+        self.send(&stop_reply(SIGTRAP));
+        true
+    }
+
+    /// Tells the debugger the machine stopped on an error.
+    pub fn fault(&mut self) {
+        if self.client.is_some() && !self.halted {
+            self.halted = true;
+            self.send(&stop_reply(SIGILL));
+        }
+    }
+
+    // Answers a command, `None` for commands answered later (continue) or
+    // never (kill)
+    fn handle(&mut self, command: &str, machine: &mut Machine) -> Option<String> {
+        let (kind, args) = command.split_at(command.chars().next().map_or(0, char::len_utf8));
+        let reply = match kind {
+            "?" => stop_reply(SIGTRAP),
+            "g" => (0..REGISTERS).map(|r| read_register(machine, r)).collect(),
+            "G" => {
+                // Nothing is written unless every register is given
+                let length: usize = (0..REGISTERS).map(|r| register_width(r) * 2).sum();
+                if args.get(..length).and_then(parse_hex).is_none() {
+                    return Some(String::from("E01"));
+                }
+                let mut offset = 0;
+                let mut written = true;
+                for r in 0..REGISTERS {
+                    let width = register_width(r) * 2;
+                    written &= write_register(machine, r, &args[offset..offset + width]);
+                    offset += width;
+                }
+                String::from(if written { "OK" } else { "E01" })
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(r) if r < REGISTERS => read_register(machine, r),
+                _ => String::from("E01"),
+            },
+            "P" => match args.split_once('=').map(|(r, v)| (usize::from_str_radix(r, 16), v)) {
+                Some((Ok(r), value)) if r < REGISTERS && write_register(machine, r, value) => String::from("OK"),
+                _ => String::from("E01"),
+            },
+            "m" => match parse_range(args) {
+                Some((address, length)) => (address..address + length)
+                    .map(|a| format!("{:02x}", machine.memory()[a]))
+                    .collect(),
+                None => String::from("E01"),
+            },
+            "M" => {
+                let written = args.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_range(range)?;
+                    let bytes = parse_hex(data)?;
+                    if bytes.len() != length {
+                        return None;
+                    }
+                    for (i, byte) in bytes.into_iter().enumerate() {
+                        machine.poke(address + i, byte);
+                    }
+                    Some(())
+                });
+                String::from(if written.is_some() { "OK" } else { "E01" })
+            }
+            "c" => {
+                self.resumed_at = Some(machine.pc as u16);
+                self.halted = false;
+                return None;
+            }
+            "s" => {
+                let keys = machine.key_map;
+                return Some(match machine.run(keys) {
+                    Ok(_) => stop_reply(SIGTRAP),
+                    Err(_) => stop_reply(SIGILL),
+                });
+            }
+            "Z" | "z" => match args.split_once(',') {
+                Some(("0", args)) => match parse_breakpoint(args) {
+                    Some(address) => {
+                        if kind == "Z" {
+                            self.breakpoints.insert(address);
+                        } else {
+                            self.breakpoints.remove(&address);
+                        }
+                        String::from("OK")
+                    }
+                    None => String::from("E01"),
+                },
+                // Only software breakpoints are supported
+                _ => String::new(),
+            },
+            "k" => {
+                self.detach();
+                return None;
+            }
+            "D" => {
+                self.send("OK");
+                self.detach();
+                return None;
+            }
+            "H" => String::from("OK"),
+            "q" => query(args),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn detach(&mut self) {
+        self.client = None;
+        self.unsent.clear();
+        self.breakpoints.clear();
+        self.halted = false;
+        self.resumed_at = None;
+    }
+
+    fn send(&mut self, reply: &str) {
+        let checksum = reply.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", reply, checksum);
+        self.send_bytes(packet.as_bytes());
+    }
+
+    fn send_bytes(&mut self, bytes: &[u8]) {
+        if self.client.is_none() {
+            return;
+        }
+        self.unsent.extend_from_slice(bytes);
+        self.flush();
+    }
+
+    // Writes what the client can take without blocking, the rest waiting
+    // for the next poll. A client that stops reading altogether is dropped
+    fn flush(&mut self) {
+        let client = match self.client.as_mut() {
+            Some(client) => client,
+            None => return,
+        };
+        let mut written = 0;
+        while written < self.unsent.len() {
+            match client.write(&self.unsent[written..]) {
+                Ok(0) => return self.detach(),
+                Ok(n) => written += n,
+                Err(ref why) if why.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return self.detach(),
+            }
+        }
+        self.unsent.drain(..written);
+        if self.unsent.len() > MAX_UNSENT {
+            self.detach();
+        }
+    }
+
+    // Takes the next interrupt or complete packet out of the received
+    // bytes, acknowledging packets as they come
+    fn next_packet(&mut self) -> Option<Packet> {
+        // Packets with a bad checksum are nacked and skipped
+        loop {
+            // Acknowledgements and noise are dropped in one go
+            match self.received.iter().position(|&b| b == 0x03 || b == b'$') {
+                Some(start) => {
+                    self.received.drain(..start);
+                }
+                None => {
+                    self.received.clear();
+                    return None;
+                }
+            }
+            if self.received[0] == 0x03 {
+                self.received.remove(0);
+                return Some(Packet::Interrupt);
+            }
+
+            let end = self.received.iter().position(|&b| b == b'#')?;
+            if self.received.len() < end + 3 {
+                return None;
+            }
+            let packet: Vec<u8> = self.received.drain(..end + 3).collect();
+            let data = unescape(&packet[1..end]);
+            let checksum = std::str::from_utf8(&packet[end + 1..]).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+            let valid = checksum == Some(packet[1..end].iter().fold(0u8, |sum, &b| sum.wrapping_add(b)));
+
+            self.send_bytes(if valid { b"+" } else { b"-" });
+            if valid {
+                return Some(Packet::Command(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+}
+
+enum Packet {
+    // Ctrl+C in gdb
+    Interrupt,
+    Command(String),
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn query(args: &str) -> String {
+    if args.starts_with("Supported") {
+        String::from("PacketSize=1000;qXfer:features:read+;swbreak+")
+    } else if let Some(annex) = args.strip_prefix("Xfer:features:read:target.xml:") {
+        match parse_offset(annex) {
+            Some((offset, length)) => {
+                let xml = target_xml();
+                let part: String = xml.chars().skip(offset).take(length).collect();
+                let last = offset + part.len() >= xml.len();
+                format!("{}{}", if last { 'l' } else { 'm' }, part)
+            }
+            None => String::from("E01"),
+        }
+    } else if args == "Attached" {
+        String::from("1")
+    } else {
+        String::new()
+    }
+}
+
+/// The registers as a gdb target description, with 16 bit registers in
+/// little endian.
+pub fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>\n",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+        "<target version=\"1.0\">\n",
+        "  <feature name=\"org.chip8.core\">\n",
+    ));
+    for r in 0..16 {
+        xml.push_str(&format!("    <reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\" regnum=\"{}\"/>\n", r, r));
+    }
+    xml.push_str(concat!(
+        "    <reg name=\"i\" bitsize=\"16\" type=\"data_ptr\" regnum=\"16\"/>\n",
+        "    <reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\" regnum=\"17\"/>\n",
+        "    <reg name=\"sp\" bitsize=\"8\" type=\"uint8\" regnum=\"18\"/>\n",
+        "  </feature>\n",
+        "</target>\n",
+    ));
+    xml
+}
+
+fn register_width(register: usize) -> usize {
+    match register {
+        REGISTER_I | REGISTER_PC => 2,
+        _ => 1,
+    }
+}
+
+fn read_register(machine: &Machine, register: usize) -> String {
+    match register {
+        REGISTER_I => hex_le(machine.i),
+        REGISTER_PC => hex_le(machine.pc as u16),
+        REGISTER_SP => format!("{:02x}", machine.stack.len()),
+        r => format!("{:02x}", machine.registers[r]),
+    }
+}
+
+// The stack depth can't be written, only the frames themselves change it
+fn write_register(machine: &mut Machine, register: usize, value: &str) -> bool {
+    let bytes = match parse_hex(value) {
+        Some(bytes) if bytes.len() == register_width(register) => bytes,
+        _ => return false,
+    };
+    let word = || (u16::from_le_bytes([bytes[0], bytes[1]]) & 0xFFF) as usize;
+    match register {
+        REGISTER_I => machine.i = word() as u16,
+        REGISTER_PC => machine.pc = word(),
+        REGISTER_SP => return bytes[0] as usize == machine.stack.len(),
+        r => machine.registers[r] = bytes[0],
+    }
+    true
+}
+
+fn hex_le(value: u16) -> String {
+    value.to_le_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// "addr,length" within the 4 KiB of memory
+fn parse_range(args: &str) -> Option<(usize, usize)> {
+    let (address, length) = parse_offset(args)?;
+    if address.checked_add(length)? > 4096 {
+        return None;
+    }
+    Some((address, length))
+}
+
+fn parse_offset(args: &str) -> Option<(usize, usize)> {
+    let (first, second) = args.split_once(',')?;
+    Some((usize::from_str_radix(first, 16).ok()?, usize::from_str_radix(second, 16).ok()?))
+}
+
+// "addr,kind" of Z0 and z0
+fn parse_breakpoint(args: &str) -> Option<u16> {
+    let (address, _) = args.split_once(',')?;
+    u16::from_str_radix(address, 16).ok().filter(|&a| a <= 0xFFF)
+}
+
+// Bytes escaped with '}' are xored with 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut escaped = false;
+    for &b in data {
+        if escaped {
+            bytes.push(b ^ 0x20);
+            escaped = false;
+        } else if b == b'}' {
+            escaped = true;
+        } else {
+            bytes.push(b);
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(program: &[u8]) -> Machine {
+        let mut m = Machine::new();
+        m.load_rom(program).unwrap();
+        m
+    }
+
+    fn stub() -> GdbStub {
+        GdbStub::bind(0).unwrap()
+    }
+
+    #[test]
+    fn registers_are_read_and_written() {
+        let mut m = machine(&[0x60, 0x12]);
+        let mut gdb = stub();
+        m.registers[0xF] = 0xAB;
+        m.i = 0x345;
+
+        let registers = gdb.handle("g", &mut m).unwrap();
+        assert_eq!(registers.len(), (16 + 2 + 2 + 1) * 2);
+        // VF, then I and PC in little endian and the stack depth
+        assert_eq!(&registers[30..], "ab4503000200");
+
+        assert_eq!(gdb.handle("P11=0603", &mut m).unwrap(), "OK");
+        assert_eq!(m.pc, 0x306);
+        assert_eq!(gdb.handle("p10", &mut m).unwrap(), "4503");
+        assert_eq!(gdb.handle("P12=05", &mut m).unwrap(), "E01");
+        assert_eq!(gdb.handle("G0102", &mut m).unwrap(), "E01");
+        assert_eq!(m.registers[0], 0);
+    }
+
+    #[test]
+    fn memory_is_read_and_written() {
+        let mut m = machine(&[0x60, 0x12]);
+        let mut gdb = stub();
+        assert_eq!(gdb.handle("m200,2", &mut m).unwrap(), "6012");
+        assert_eq!(gdb.handle("M200,2:6134", &mut m).unwrap(), "OK");
+        assert_eq!(m.run([false; 16]), Ok(0x6134));
+        assert_eq!(m.registers[1], 0x34);
+        assert_eq!(gdb.handle("mfff,2", &mut m).unwrap(), "E01");
+        assert_eq!(gdb.handle("m2,ffffffffffffffff", &mut m).unwrap(), "E01");
+    }
+
+    #[test]
+    fn steps_and_breakpoints_stop_the_machine() {
+        // 0x200: V0 += 1, 0x202: jump 0x200
+        let mut m = machine(&[0x70, 0x01, 0x12, 0x00]);
+        let mut gdb = stub();
+        assert_eq!(gdb.handle("s", &mut m).unwrap(), "S05");
+        assert_eq!(m.pc, 0x202);
+
+        assert_eq!(gdb.handle("Z0,200,2", &mut m).unwrap(), "OK");
+        assert_eq!(gdb.handle("Z1,200,2", &mut m).unwrap(), "");
+        assert_eq!(gdb.handle("Z0,1000,2", &mut m).unwrap(), "E01");
+        let _client = TcpStream::connect(("127.0.0.1", gdb.port())).unwrap();
+        assert_eq!(gdb.poll(&mut m), Some(GdbEvent::Attached));
+        assert!(gdb.is_halted());
+        assert!(gdb.handle("c", &mut m).is_none());
+        assert!(!gdb.is_halted());
+
+        assert!(!gdb.check_breakpoint(&m));
+        m.run([false; 16]).unwrap();
+        assert!(gdb.check_breakpoint(&m));
+        assert!(gdb.is_halted());
+
+        // Continuing from the breakpoint doesn't stop on it again
+        gdb.handle("c", &mut m);
+        assert!(!gdb.check_breakpoint(&m));
+    }
+
+    #[test]
+    fn packets_are_framed_and_acknowledged() {
+        let mut m = machine(&[0x60, 0x12]);
+        let mut gdb = stub();
+        let mut client = TcpStream::connect(("127.0.0.1", gdb.port())).unwrap();
+        client.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+
+        client.write_all(b"$?#3f").unwrap();
+        let mut reply = Vec::new();
+        let mut buffer = [0; 64];
+        while !reply.ends_with(b"#b8") {
+            gdb.poll(&mut m);
+            if let Ok(n) = client.read(&mut buffer) {
+                reply.extend_from_slice(&buffer[..n]);
+            }
+        }
+        assert_eq!(reply, b"+$S05#b8");
+        assert!(gdb.is_halted());
+    }
+
+    #[test]
+    fn noise_and_corrupted_packets_are_skipped() {
+        let mut gdb = stub();
+        let mut received = b"+-".repeat(10_000);
+        received.extend_from_slice(&b"$?#00".repeat(10_000));
+        received.extend_from_slice(b"+$g#67\x03");
+        gdb.received = received;
+
+        assert!(matches!(gdb.next_packet(), Some(Packet::Command(c)) if c == "g"));
+        assert!(matches!(gdb.next_packet(), Some(Packet::Interrupt)));
+        assert!(gdb.next_packet().is_none());
+        assert!(gdb.received.is_empty());
+    }
+
+    #[test]
+    fn clients_that_stop_reading_are_dropped_without_blocking() {
+        let mut m = machine(&[0x60, 0x12]);
+        let mut gdb = stub();
+        let _client = TcpStream::connect(("127.0.0.1", gdb.port())).unwrap();
+        while gdb.poll(&mut m) != Some(GdbEvent::Attached) {}
+
+        let reply = "0".repeat(1 << 16);
+        for _ in 0..1024 {
+            if gdb.client.is_none() {
+                break;
+            }
+            gdb.send(&reply);
+        }
+        assert!(gdb.client.is_none());
+    }
+
+    #[test]
+    fn the_target_description_is_served_in_parts() {
+        let xml = target_xml();
+        let first = query("Xfer:features:read:target.xml:0,20");
+        assert_eq!(first, format!("m{}", &xml[..0x20]));
+        let rest = query(&format!("Xfer:features:read:target.xml:20,{:x}", xml.len()));
+        assert_eq!(rest, format!("l{}", &xml[0x20..]));
+    }
+}
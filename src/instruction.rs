@@ -1,3 +1,5 @@
+use std::fmt;
+
 
 type Word = u16;
 
@@ -12,8 +14,6 @@ fn join_2_bytes (left: u8, right: u8) -> u16 {
 
 pub trait DecodableInstruction {
     fn new(left: u8, right: u8) -> Self;
-
-    fn match_masked(&self, mask: u16, matcher: u16) -> bool;
 }
 
 impl DecodableInstruction for Chip8Instruction {
@@ -29,8 +29,150 @@ impl DecodableInstruction for Chip8Instruction {
             ]
         }
     }
+}
 
-    fn match_masked(&self, mask: u16, matcher: u16) -> bool {
-        (self.raw & mask) == matcher
+impl Chip8Instruction {
+    pub fn decode(&self) -> Opcode {
+        Opcode::decode(self.raw)
     }
-}
\ No newline at end of file
+}
+
+/// An instruction decoded into its operation and operands, so executing it
+/// is a single `match`. Registers are given by index, addresses by value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Opcode {
+    ClearScreen,                // 00E0
+    Return,                     // 00EE
+    Jump(u16),                  // 1NNN
+    Call(u16),                  // 2NNN
+    SkipEqual(u8, u8),          // 3XNN
+    SkipNotEqual(u8, u8),       // 4XNN
+    SkipRegEqual(u8, u8),       // 5XY0
+    SetRegister(u8, u8),        // 6XNN
+    AddValue(u8, u8),           // 7XNN
+    SetReg(u8, u8),             // 8XY0
+    Or(u8, u8),                 // 8XY1
+    And(u8, u8),                // 8XY2
+    Xor(u8, u8),                // 8XY3
+    AddReg(u8, u8),             // 8XY4
+    SubReg(u8, u8),             // 8XY5
+    ShiftRight(u8, u8),         // 8XY6
+    SubN(u8, u8),               // 8XY7
+    ShiftLeft(u8, u8),          // 8XYE
+    SkipRegNotEqual(u8, u8),    // 9XY0
+    SetIndex(u16),              // ANNN
+    JumpPlus(u16),              // BNNN
+    Random(u8, u8),             // CXNN
+    Display(u8, u8, u8),        // DXYN
+    SkipKey(u8),                // EX9E
+    SkipNotKey(u8),             // EXA1
+    GetDelay(u8),               // FX07
+    GetKey(u8),                 // FX0A
+    SetDelay(u8),               // FX15
+    SetSound(u8),               // FX18
+    AddIndex(u8),               // FX1E
+    Font(u8),                   // FX29
+    Bcd(u8),                    // FX33
+    RegDump(u8),                // FX55
+    RegLoad(u8),                // FX65
+    // Anything else, executed as a no-op
+    Unknown,
+}
+
+impl Opcode {
+    pub fn decode(raw: Word) -> Self {
+        let x = ((raw & 0x0F00) >> 8) as u8;
+        let y = ((raw & 0x00F0) >> 4) as u8;
+        let n = (raw & 0x000F) as u8;
+        let nn = (raw & 0x00FF) as u8;
+        let nnn = raw & 0x0FFF;
+
+        match raw >> 12 {
+            0x0 if raw == 0x00E0 => Opcode::ClearScreen,
+            0x0 if raw == 0x00EE => Opcode::Return,
+            0x1 => Opcode::Jump(nnn),
+            0x2 => Opcode::Call(nnn),
+            0x3 => Opcode::SkipEqual(x, nn),
+            0x4 => Opcode::SkipNotEqual(x, nn),
+            0x5 if n == 0x0 => Opcode::SkipRegEqual(x, y),
+            0x6 => Opcode::SetRegister(x, nn),
+            0x7 => Opcode::AddValue(x, nn),
+            0x8 => match n {
+                0x0 => Opcode::SetReg(x, y),
+                0x1 => Opcode::Or(x, y),
+                0x2 => Opcode::And(x, y),
+                0x3 => Opcode::Xor(x, y),
+                0x4 => Opcode::AddReg(x, y),
+                0x5 => Opcode::SubReg(x, y),
+                0x6 => Opcode::ShiftRight(x, y),
+                0x7 => Opcode::SubN(x, y),
+                0xE => Opcode::ShiftLeft(x, y),
+                _ => Opcode::Unknown,
+            },
+            0x9 if n == 0x0 => Opcode::SkipRegNotEqual(x, y),
+            0xA => Opcode::SetIndex(nnn),
+            0xB => Opcode::JumpPlus(nnn),
+            0xC => Opcode::Random(x, nn),
+            0xD => Opcode::Display(x, y, n),
+            0xE if nn == 0x9E => Opcode::SkipKey(x),
+            0xE if nn == 0xA1 => Opcode::SkipNotKey(x),
+            0xF => match nn {
+                0x07 => Opcode::GetDelay(x),
+                0x0A => Opcode::GetKey(x),
+                0x15 => Opcode::SetDelay(x),
+                0x18 => Opcode::SetSound(x),
+                0x1E => Opcode::AddIndex(x),
+                0x29 => Opcode::Font(x),
+                0x33 => Opcode::Bcd(x),
+                0x55 => Opcode::RegDump(x),
+                0x65 => Opcode::RegLoad(x),
+                _ => Opcode::Unknown,
+            },
+            _ => Opcode::Unknown,
+        }
+    }
+}
+
+/// Mnemonics after Cowgod's Chip-8 reference, addresses and bytes in
+/// hexadecimal, e.g. `LD V3, 0x2A` or `DRW V0, V1, 5`.
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Opcode::ClearScreen => write!(f, "CLS"),
+            Opcode::Return => write!(f, "RET"),
+            Opcode::Jump(nnn) => write!(f, "JP 0x{:03X}", nnn),
+            Opcode::Call(nnn) => write!(f, "CALL 0x{:03X}", nnn),
+            Opcode::SkipEqual(x, nn) => write!(f, "SE V{:X}, 0x{:02X}", x, nn),
+            Opcode::SkipNotEqual(x, nn) => write!(f, "SNE V{:X}, 0x{:02X}", x, nn),
+            Opcode::SkipRegEqual(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Opcode::SetRegister(x, nn) => write!(f, "LD V{:X}, 0x{:02X}", x, nn),
+            Opcode::AddValue(x, nn) => write!(f, "ADD V{:X}, 0x{:02X}", x, nn),
+            Opcode::SetReg(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Opcode::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Opcode::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Opcode::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Opcode::AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Opcode::SubReg(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Opcode::ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Opcode::SubN(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Opcode::ShiftLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Opcode::SkipRegNotEqual(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Opcode::SetIndex(nnn) => write!(f, "LD I, 0x{:03X}", nnn),
+            Opcode::JumpPlus(nnn) => write!(f, "JP V0, 0x{:03X}", nnn),
+            Opcode::Random(x, nn) => write!(f, "RND V{:X}, 0x{:02X}", x, nn),
+            Opcode::Display(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Opcode::SkipKey(x) => write!(f, "SKP V{:X}", x),
+            Opcode::SkipNotKey(x) => write!(f, "SKNP V{:X}", x),
+            Opcode::GetDelay(x) => write!(f, "LD V{:X}, DT", x),
+            Opcode::GetKey(x) => write!(f, "LD V{:X}, K", x),
+            Opcode::SetDelay(x) => write!(f, "LD DT, V{:X}", x),
+            Opcode::SetSound(x) => write!(f, "LD ST, V{:X}", x),
+            Opcode::AddIndex(x) => write!(f, "ADD I, V{:X}", x),
+            Opcode::Font(x) => write!(f, "LD F, V{:X}", x),
+            Opcode::Bcd(x) => write!(f, "LD B, V{:X}", x),
+            Opcode::RegDump(x) => write!(f, "LD [I], V{:X}", x),
+            Opcode::RegLoad(x) => write!(f, "LD V{:X}, [I]", x),
+            Opcode::Unknown => write!(f, "???"),
+        }
+    }
+}
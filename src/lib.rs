@@ -0,0 +1,14 @@
+pub mod cpu;
+pub mod instruction;
+pub mod palette;
+pub mod database;
+pub mod cartridge;
+pub mod octo;
+pub mod quirks;
+pub mod stack;
+pub mod capture;
+pub mod replay;
+pub mod gdb;
+pub mod source_map;
+pub mod dap;
+pub mod external;
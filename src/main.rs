@@ -1,68 +1,638 @@
-mod cpu;
-mod instruction;
-mod external;
-
-use cpu::Chip8Machine;
+use chip8::capture;
+use chip8::cpu;
+use chip8::cpu::Chip8Machine;
+use chip8::database::Database;
+use chip8::external;
+use chip8::gdb::{GdbEvent, GdbStub};
+use chip8::palette::PALETTES;
+use chip8::quirks::{Quirks, PRESETS};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::env;
+use std::process;
 
+use std::fs;
 use std::fs::File;
-use std::io::prelude::*;
-use std::path::Path;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
+// Dumps the machine after every instruction, buffered since it is written
+// hundreds of times per frame
 struct Logger {
-    pub f: File,
+    f: BufWriter<File>,
 }
 
 impl Logger {
-    fn new(path: &Path) -> Self {
-        match File::create(path) {
-            Ok(f) => Logger { f },
-            Err(why) => panic!("Couldn't open/create file {}: {}", path.display(), why),
+    fn create(path: &Path) -> io::Result<Self> {
+        Ok(Logger { f: BufWriter::new(File::create(path)?) })
+    }
+
+    fn log_machine(&mut self, m: &cpu::Machine, last_instruction: u16) -> io::Result<()> {
+        write!(self.f, "\nINSTRUCTION: {:#x}", last_instruction)?;
+        write!(self.f, "\nI: {} |", m.i)?;
+        write!(self.f, "Timer: {} |", m.delay_timer)?;
+        write!(self.f, "pc: {} | ", m.pc)?;
+        write!(self.f, "sp: {} | ", m.stack.len())?;
+
+        write!(self.f, "\nStack:")?;
+        for frame in m.stack.frames() {
+            write!(self.f, "{} | ", frame.caller)?;
         }
+
+        write!(self.f, "\nRegisters:")?;
+        for (i, register) in m.registers.iter().enumerate() {
+            write!(self.f, "{:#x}: {} | ", i, register)?;
+        }
+        Ok(())
     }
+}
 
-    fn log_machine(&mut self, m: &cpu::Machine, last_instruction: u16) {
-        self.f.write(format!("\nINSTRUCTION: {:#x}", last_instruction).as_bytes());
-        self.f.write_all(format!("\nI: {} |", m.i).as_bytes());
-        self.f.write_all(format!("Timer: {} |", m.delay_timer).as_bytes());
-        self.f.write_all(format!("pc: {} | ", m.pc).as_bytes());
-        self.f.write_all(format!("sp: {} | ", m.sp).as_bytes());
+struct Options {
+    rom_path: String,
+    persistence: external::Persistence,
+    scale: Option<u32>,
+    integer_scaling: bool,
+    fullscreen: bool,
+    effects: external::Effects,
+    // Unless given, the database's settings for the ROM are used
+    palette: Option<usize>,
+    record: Option<String>,
+    audio: Option<String>,
+    quirks: Option<Quirks>,
+    counter: bool,
+    rom_dir: Option<String>,
+    database: Option<String>,
+    gdb: Option<u16>,
+    debugger: bool,
+    dump: Option<String>,
+}
 
-        self.f.write_all(String::from("\nStack:").as_bytes());
-        for i in m.stack.iter() {
-            self.f.write(format!("{} | ", i).as_bytes());
+fn parse_strength(flag: &str, value: Option<&String>) -> Option<f32> {
+    match value.map(|v| v.parse::<f32>()) {
+        Some(Ok(s)) if (0.0..=1.0).contains(&s) => Some(s),
+        _ => panic!("{} expects a strength between 0 and 1", flag),
+    }
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Self {
+        let mut rom_path = None;
+        let mut persistence = external::Persistence::default();
+        let mut scale = None;
+        let mut integer_scaling = false;
+        let mut fullscreen = false;
+        let mut effects = external::Effects::default();
+        let mut palette = None;
+        let mut record = None;
+        let mut audio = None;
+        let mut quirks = None;
+        let mut counter = false;
+        let mut rom_dir = None;
+        let mut database = None;
+        let mut gdb = None;
+        let mut debugger = false;
+        let mut dump = None;
+
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--persistence" => {
+                    persistence = match args.next().map(String::as_str) {
+                        Some("off") => external::Persistence::Off,
+                        Some("blend") => external::Persistence::Blend,
+                        Some(decay) => match decay.parse::<f32>() {
+                            Ok(d) if d > 0.0 && d <= 1.0 => external::Persistence::Decay(d),
+                            _ => panic!("--persistence expects off, blend or a decay in (0, 1]"),
+                        },
+                        None => panic!("--persistence expects off, blend or a decay in (0, 1]"),
+                    }
+                }
+                "--scale" => {
+                    scale = match args.next().map(|s| s.parse::<u32>()) {
+                        Some(Ok(s)) if s > 0 => Some(s),
+                        _ => panic!("--scale expects a positive number of window pixels per pixel"),
+                    }
+                }
+                "--integer-scaling" => integer_scaling = true,
+                "--fullscreen" => fullscreen = true,
+                "--palette" => {
+                    palette = match args.next().and_then(|name| PALETTES.iter().position(|(n, _)| n == name)) {
+                        Some(index) => Some(index),
+                        None => panic!("--palette expects one of: {}", PALETTES.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(", ")),
+                    }
+                }
+                "--record" => record = Some(args.next().expect("--record expects a .gif file or a directory").clone()),
+                "--audio" => audio = Some(args.next().expect("--audio expects a .wav file").clone()),
+                "--quirks" => {
+                    quirks = match args.next().and_then(|name| Quirks::by_name(name)) {
+                        Some(q) => Some(q),
+                        None => panic!("--quirks expects one of: default, chip8, schip, xochip"),
+                    }
+                }
+                "--fps" => counter = true,
+                "--rom-dir" => rom_dir = Some(args.next().expect("--rom-dir expects a directory").clone()),
+                "--database" => database = Some(args.next().expect("--database expects a programs.json file").clone()),
+                "--gdb" => {
+                    gdb = match args.next().map(|p| p.parse::<u16>()) {
+                        Some(Ok(port)) => Some(port),
+                        _ => panic!("--gdb expects a TCP port"),
+                    }
+                }
+                "--debugger" => debugger = true,
+                "--dump" => dump = Some(args.next().expect("--dump expects a file").clone()),
+                "--crt" => effects = external::Effects::crt(),
+                "--scanlines" => effects.scanlines = parse_strength(arg, args.next()),
+                "--grid" => effects.grid = parse_strength(arg, args.next()),
+                "--bloom" => effects.bloom = parse_strength(arg, args.next()),
+                "--vignette" => effects.vignette = parse_strength(arg, args.next()),
+                _ => rom_path = Some(arg.clone()),
+            }
         }
 
-        self.f.write_all(String::from("\nRegisters:").as_bytes());
-        for i in 0..15 {
-            self.f.write(format!("{:#x}: {} | ", i, m.registers[i]).as_bytes());
+        Options {
+            rom_path: rom_path.expect("Usage: sdl-chip-8 [options] <path to ROM>"),
+            persistence,
+            scale,
+            integer_scaling,
+            fullscreen,
+            effects,
+            palette,
+            record,
+            audio,
+            quirks,
+            counter,
+            rom_dir,
+            database,
+            gdb,
+            debugger,
+            dump,
         }
     }
 }
 
-fn main() {
-    let sleep_duration = Duration::from_millis(2);
+// Messages go to stdout and the OSD
+fn report(display: &mut external::Screen, message: &str) {
+    println!("{}", message);
+    display.notify(message);
+}
+
+fn start_recording(path: &Path, display: &mut external::Screen) -> Option<capture::Recorder> {
+    match capture::Recorder::start(path, &display.palette(), RECORD_SCALE) {
+        Ok(recorder) => {
+            report(display, &format!("Recording to {}", path.display()));
+            Some(recorder)
+        }
+        Err(why) => {
+            report(display, &why);
+            None
+        }
+    }
+}
+
+fn stop_recording(recorder: capture::Recorder, display: &mut external::Screen) {
+    let frames = recorder.frames();
+    match recorder.finish() {
+        Ok(()) => report(display, &format!("Recorded {} frames", frames)),
+        Err(why) => report(display, &why),
+    }
+}
+
+// Size of recorded pixels, recordings at window scale would be huge
+const RECORD_SCALE: usize = 4;
+// One 60 Hz frame; the screen is presented and timers tick once per frame
+const FRAME_DURATION: Duration = Duration::from_micros(16_667);
+
+fn title(pacing: &external::Pacing, percent: u32) -> String {
+    let state = match (pacing.is_paused(), pacing.speed()) {
+        (true, _) => String::from("Paused"),
+        (false, external::Speed::Normal) => format!("{}%", percent),
+        (false, external::Speed::Turbo) => format!("Turbo {}%", percent),
+        (false, external::Speed::Slow(n)) => format!("Slow motion 1/{} {}%", n, percent),
+    };
+    format!("Chip 8 Emulator - {}", state)
+}
+
+// Top right corner of the OSD: what stopped the emulation, or the speed
+// when it isn't normal and the frame counter when enabled
+fn status(pacing: &external::Pacing, meter: &external::SpeedMeter, counter: bool, error: &Option<String>, debugged: bool) -> Option<String> {
+    if let Some(ref error) = *error {
+        return Some(error.clone());
+    }
+    if debugged {
+        return Some(String::from("Stopped by the debugger"));
+    }
+    let mut parts = Vec::new();
+    match (pacing.is_paused(), pacing.speed()) {
+        (true, _) => parts.push(String::from("Paused")),
+        (false, external::Speed::Normal) => {}
+        (false, external::Speed::Turbo) => parts.push(format!("Turbo {}%", meter.percent())),
+        (false, external::Speed::Slow(n)) => parts.push(format!("Slow 1/{}", n)),
+    }
+    if counter {
+        parts.push(format!("{:.0} FPS {} IPS", meter.fps(), meter.ips()));
+    }
+    if parts.is_empty() { None } else { Some(parts.join("  ")) }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MenuItem {
+    Resume,
+    Reset,
+    HardReset,
+    Save,
+    Load,
+    Quirks,
+    Palette,
+    Speed,
+    Browse,
+    Quit,
+    // A file of the ROM browser
+    Rom(usize),
+    // One of the ROMs of the archive or directory picked in the browser
+    ArchiveRom(usize),
+}
+
+// In-memory save states, lost when another ROM is loaded
+const SAVE_SLOTS: usize = 4;
+const BROWSED_EXTENSIONS: [&str; 3] = ["ch8", "zip", "gz"];
+// Longer ROM names are cut in the browser so the menu fits the screen
+const MAX_ROM_NAME: usize = 40;
 
-    let mut log = Logger::new(Path::new("./dump-machine.txt"));
+const SPEEDS: [(&str, external::Speed); 4] = [
+    ("normal", external::Speed::Normal),
+    ("turbo", external::Speed::Turbo),
+    ("slow 1/2", external::Speed::Slow(2)),
+    ("slow 1/4", external::Speed::Slow(4)),
+];
 
+fn main_menu(quirks: Quirks, palette: usize, speed: external::Speed, slot: usize) -> external::Menu<MenuItem> {
+    let slots: Vec<String> = (1..=SAVE_SLOTS).map(|n| n.to_string()).collect();
+    let preset = PRESETS.iter().position(|&(_, q)| q == quirks).unwrap_or(0);
+    let speed = SPEEDS.iter().position(|&(_, s)| s == speed).unwrap_or(0);
+    external::Menu::new("Paused")
+        .action(MenuItem::Resume, "Resume")
+        .action(MenuItem::Reset, "Reset")
+        .action(MenuItem::HardReset, "Hard reset")
+        .choice(MenuItem::Save, "Save state", slots.clone(), slot)
+        .choice(MenuItem::Load, "Load state", slots, slot)
+        .choice(MenuItem::Quirks, "Quirks", PRESETS.iter().map(|(n, _)| n.to_string()).collect(), preset)
+        .choice(MenuItem::Palette, "Palette", PALETTES.iter().map(|(n, _)| n.to_string()).collect(), palette)
+        .choice(MenuItem::Speed, "Speed", SPEEDS.iter().map(|(n, _)| n.to_string()).collect(), speed)
+        .action(MenuItem::Browse, "Load ROM")
+        .action(MenuItem::Quit, "Quit")
+}
+
+fn rom_menu(roms: &[PathBuf]) -> external::Menu<MenuItem> {
+    roms.iter().enumerate().fold(external::Menu::new("Load ROM"), |menu, (i, path)| {
+        let name: String = path.file_name().unwrap_or_default().to_string_lossy().chars().take(MAX_ROM_NAME).collect();
+        menu.action(MenuItem::Rom(i), &name)
+    })
+}
+
+fn archive_menu(path: &str, names: &[String]) -> external::Menu<MenuItem> {
+    let title: String = capture::rom_name(path).chars().take(MAX_ROM_NAME).collect();
+    names.iter().enumerate().fold(external::Menu::new(&title), |menu, (i, name)| {
+        let name: String = name.chars().take(MAX_ROM_NAME).collect();
+        menu.action(MenuItem::ArchiveRom(i), &name)
+    })
+}
+
+// The *.ch8 files and archives of `dir`, sorted by name
+fn list_roms(dir: &Path) -> Vec<PathBuf> {
+    let mut roms: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|e| BROWSED_EXTENSIONS.iter().any(|b| e.eq_ignore_ascii_case(b))))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    roms.sort();
+    roms
+}
+
+fn start_machine(bytes: &[u8], quirks: Quirks) -> Result<cpu::Machine, &'static str> {
+    let mut machine: cpu::Machine = cpu::Machine::new();
+    machine.quirks = quirks;
+    machine.load_rom(bytes)?;
+    Ok(machine)
+}
+
+fn main() {
     let args: Vec<String> = env::args().collect();
-    let cartridge_filename = &args[1];
+    let options = Options::parse(&args);
+
+    let mut rom_path: String = options.rom_path.clone();
+    let database = match options.database {
+        Some(ref path) => match Database::load(Path::new(path)) {
+            Ok(database) => database,
+            Err(why) => {
+                eprintln!("{}", why);
+                process::exit(1);
+            }
+        },
+        None => Database::bundled(),
+    };
+    let rom = match external::load_rom(&rom_path, &database, external::prompt_rom) {
+        Ok(rom) => rom,
+        Err(why) => {
+            eprintln!("{}", why);
+            process::exit(1);
+        }
+    };
+    let metadata = rom.metadata.clone().unwrap_or_default();
 
-    let file_path: String = String::from(cartridge_filename);
-    let bytes = external::load_rom(&file_path);
+    let mut chip8_machine = match start_machine(&rom.bytes, options.quirks.or(metadata.quirks).unwrap_or_default()) {
+        Ok(machine) => machine,
+        Err(why) => {
+            eprintln!("Couldn't load {}: {}", rom_path, why);
+            process::exit(1);
+        }
+    };
+    let mut tickrate = metadata.tickrate.unwrap_or(cpu::INSTRUCTIONS_PER_FRAME);
 
-    let mut chip8_machine: cpu::Machine = cpu::Machine::new();
-    chip8_machine.load_rom(&bytes).unwrap();
-    
+    // The browser lists the directory of the first ROM unless told otherwise
+    let rom_dir = match options.rom_dir {
+        Some(ref dir) => PathBuf::from(dir),
+        None => match Path::new(&rom_path).parent() {
+            Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        },
+    };
+    let mut roms = Vec::new();
+    let mut slots: Vec<Option<cpu::Machine>> = vec![None; SAVE_SLOTS];
+    let mut slot = 0;
+    let mut menu: Option<external::Menu<MenuItem>> = None;
+    // Whether the menu shows the ROM browser, backing out returns to the main menu
+    let mut browsing = false;
+    // Archive whose ROMs the menu lists, backing out returns to the browser
+    let mut archive: Option<String> = None;
 
     let sdl_context = sdl2::init().unwrap();
 
     let mut display = external::Screen::new(&sdl_context);
+    display.set_persistence(options.persistence);
+    display.set_integer_scaling(options.integer_scaling);
+    if let Some(scale) = options.scale {
+        display.set_scale(scale);
+    }
+    if options.fullscreen {
+        display.toggle_fullscreen();
+    }
+    display.set_effects(options.effects);
+    display.set_debugger_shown(options.debugger);
+    let mut palette = options.palette.unwrap_or(0);
+    display.set_palette(options.palette.map(|i| PALETTES[i].1).or(metadata.palette).unwrap_or(PALETTES[palette].1));
+    if let Some(ref metadata) = rom.metadata {
+        report(&mut display, &metadata.credits());
+    }
+
+    let mut audio = match options.audio {
+        Some(ref path) => match capture::WavRecorder::start(Path::new(path)) {
+            Ok(audio) => Some(audio),
+            Err(why) => {
+                report(&mut display, &why);
+                None
+            }
+        },
+        None => None,
+    };
+    let mut recorder = match options.record {
+        Some(ref path) => start_recording(Path::new(path), &mut display),
+        None => None,
+    };
+    // What the effects hotkey switches back on
+    let effects = if options.effects.is_enabled() { options.effects } else { external::Effects::crt() };
     let mut input = external::Input::new(&sdl_context);
+    input.set_key_hints(metadata.keys);
+    let mut pacing = external::Pacing::new();
+    let mut meter = external::SpeedMeter::new();
+    display.set_title(&title(&pacing, meter.percent()));
+    let mut counter = options.counter;
+    // Why the emulation stopped; the window stays open to show it
+    let mut error: Option<String> = None;
+    let mut log = match options.dump {
+        Some(ref path) => match Logger::create(Path::new(path)) {
+            Ok(logger) => Some(logger),
+            Err(why) => {
+                report(&mut display, &format!("Couldn't create {}: {}", path, why));
+                None
+            }
+        },
+        None => None,
+    };
+    let mut gdb = match options.gdb {
+        Some(port) => match GdbStub::bind(port) {
+            Ok(stub) => {
+                report(&mut display, &format!("Waiting for gdb on port {}", stub.port()));
+                Some(stub)
+            }
+            Err(why) => {
+                report(&mut display, &format!("Couldn't listen for gdb on port {}: {}", port, why));
+                None
+            }
+        },
+        None => None,
+    };
+
+    'emulation: while let Ok(keypad) = input.poll() {
+        let frame_start = Instant::now();
+
+        let mut status_changed = false;
+        for command in input.commands() {
+            match command {
+                external::Command::ToggleFullscreen => display.toggle_fullscreen(),
+                external::Command::ToggleIntegerScaling => {
+                    display.set_integer_scaling(!display.integer_scaling());
+                    display.notify(if display.integer_scaling() { "Integer scaling on" } else { "Integer scaling off" });
+                }
+                external::Command::ScaleUp => {
+                    display.set_scale(display.scale() + 1);
+                    display.notify(&format!("Scale {}x", display.scale()));
+                }
+                external::Command::ScaleDown => {
+                    display.set_scale(display.scale() - 1);
+                    display.notify(&format!("Scale {}x", display.scale()));
+                }
+                external::Command::ToggleEffects => if display.effects().is_enabled() {
+                    display.set_effects(external::Effects::default());
+                    display.notify("Effects off");
+                } else {
+                    display.set_effects(effects);
+                    display.notify("Effects on");
+                },
+                external::Command::NextPalette => {
+                    palette = (palette + 1) % PALETTES.len();
+                    display.set_palette(PALETTES[palette].1);
+                    display.notify(&format!("Palette {}", PALETTES[palette].0));
+                }
+                external::Command::Screenshot { native } => {
+                    let scale = if native { 1 } else { display.scale() as usize };
+                    match capture::save_screenshot(&chip8_machine.screen, &display.palette(), scale, Path::new("."), &rom_path, chip8_machine.frames) {
+                        Ok(path) => report(&mut display, &format!("Saved screenshot to {}", path.display())),
+                        Err(why) => report(&mut display, &why),
+                    }
+                }
+                external::Command::ToggleRecording => match recorder.take() {
+                    Some(recording) => stop_recording(recording, &mut display),
+                    None => {
+                        let path = capture::numbered_path(Path::new("."), &capture::rom_name(&rom_path), "gif");
+                        recorder = start_recording(&path, &mut display);
+                    }
+                },
+                external::Command::Reset { hard } => {
+                    if hard { chip8_machine.hard_reset() } else { chip8_machine.reset() }
+                    error = None;
+                    status_changed = true;
+                    display.notify(if hard { "Hard reset" } else { "Reset" });
+                }
+                external::Command::Redraw => display.refresh(),
+                external::Command::ToggleTurbo => pacing.toggle_turbo(),
+                external::Command::SlowMotion => pacing.cycle_slow_motion(),
+                external::Command::TogglePause => pacing.toggle_pause(),
+                external::Command::FrameAdvance => pacing.advance(),
+                external::Command::Step => {
+                    if !pacing.is_paused() {
+                        pacing.toggle_pause();
+                    } else if menu.is_none() && error.is_none() {
+                        // A draw waiting for the vblank gets it right away
+                        if chip8_machine.is_waiting_vblank() {
+                            chip8_machine.tick_timers();
+                        }
+                        if let Err(why) = chip8_machine.run(keypad) {
+                            error = Some(format!("Emulation stopped at {:#05x}: {}", chip8_machine.pc, why));
+                        }
+                    }
+                }
+                external::Command::ToggleDebugger => display.set_debugger_shown(!display.debugger_shown()),
+                external::Command::ToggleCounter => counter = !counter,
+                external::Command::ToggleMenu => {
+                    menu = match menu {
+                        Some(_) => None,
+                        None => Some(main_menu(chip8_machine.quirks, palette, pacing.speed(), slot)),
+                    };
+                    browsing = false;
+                    archive = None;
+                }
+                external::Command::Menu(menu_input) => {
+                    let event = menu.as_mut().and_then(|m| m.input(menu_input));
+                    // ROM to load, and which one if it is an archive
+                    let mut open: Option<(String, Option<usize>)> = None;
+                    match event {
+                        Some(external::MenuEvent::Closed) if archive.is_some() => {
+                            menu = Some(rom_menu(&roms));
+                            archive = None;
+                        }
+                        Some(external::MenuEvent::Closed) if browsing => {
+                            menu = Some(main_menu(chip8_machine.quirks, palette, pacing.speed(), slot));
+                            browsing = false;
+                        }
+                        Some(external::MenuEvent::Closed) | Some(external::MenuEvent::Selected(MenuItem::Resume)) => menu = None,
+                        Some(external::MenuEvent::Selected(item @ (MenuItem::Reset | MenuItem::HardReset))) => {
+                            let hard = item == MenuItem::HardReset;
+                            if hard { chip8_machine.hard_reset() } else { chip8_machine.reset() }
+                            error = None;
+                            status_changed = true;
+                            menu = None;
+                            display.notify(if hard { "Hard reset" } else { "Reset" });
+                        }
+                        Some(external::MenuEvent::Changed(MenuItem::Save, i))
+                        | Some(external::MenuEvent::Changed(MenuItem::Load, i)) => slot = i,
+                        Some(external::MenuEvent::Selected(MenuItem::Save)) => {
+                            slots[slot] = Some(chip8_machine.clone());
+                            display.notify(&format!("Saved state {}", slot + 1));
+                        }
+                        Some(external::MenuEvent::Selected(MenuItem::Load)) => match slots[slot] {
+                            Some(ref state) => {
+                                chip8_machine = state.clone();
+                                chip8_machine.dirty = Some(cpu::DirtyRect::full());
+                                error = None;
+                                status_changed = true;
+                                menu = None;
+                                display.notify(&format!("Loaded state {}", slot + 1));
+                            }
+                            None => display.notify(&format!("State {} is empty", slot + 1)),
+                        },
+                        Some(external::MenuEvent::Changed(MenuItem::Quirks, i)) => {
+                            chip8_machine.quirks = PRESETS[i].1;
+                            display.notify(&format!("Quirks {}", PRESETS[i].0));
+                        }
+                        Some(external::MenuEvent::Changed(MenuItem::Palette, i)) => {
+                            palette = i;
+                            display.set_palette(PALETTES[palette].1);
+                        }
+                        Some(external::MenuEvent::Changed(MenuItem::Speed, i)) => {
+                            pacing.set_speed(SPEEDS[i].1);
+                            status_changed = true;
+                        }
+                        Some(external::MenuEvent::Selected(MenuItem::Browse)) => {
+                            roms = list_roms(&rom_dir);
+                            if roms.is_empty() {
+                                display.notify(&format!("No ROMs in {}", rom_dir.display()));
+                            } else {
+                                menu = Some(rom_menu(&roms));
+                                browsing = true;
+                            }
+                        }
+                        Some(external::MenuEvent::Selected(MenuItem::Rom(i))) => open = Some((roms[i].to_string_lossy().into_owned(), None)),
+                        Some(external::MenuEvent::Selected(MenuItem::ArchiveRom(i))) => open = archive.clone().map(|path| (path, Some(i))),
+                        Some(external::MenuEvent::Selected(MenuItem::Quit)) => break 'emulation,
+                        _ => {}
+                    }
+
+                    if let Some((path, choice)) = open {
+                        // Archives and directories with several ROMs are
+                        // listed in a menu of their own to pick one from
+                        let mut offered = Vec::new();
+                        let loaded = external::load_rom(&path, &database, |names| {
+                            if choice.is_none() {
+                                offered = names.to_vec();
+                            }
+                            choice
+                        });
+                        let loaded = loaded.and_then(|rom| {
+                            let quirks = rom.metadata.as_ref().and_then(|m| m.quirks).unwrap_or(chip8_machine.quirks);
+                            start_machine(&rom.bytes, quirks).map(|m| (m, rom.metadata)).map_err(String::from)
+                        });
+                        match loaded {
+                            _ if !offered.is_empty() => {
+                                menu = Some(archive_menu(&path, &offered));
+                                archive = Some(path);
+                            }
+                            Ok((machine, metadata)) => {
+                                chip8_machine = machine;
+                                chip8_machine.dirty = Some(cpu::DirtyRect::full());
+                                rom_path = path;
+                                slots = vec![None; SAVE_SLOTS];
+                                error = None;
+                                status_changed = true;
+                                menu = None;
+                                browsing = false;
+                                archive = None;
+                                let name = match metadata {
+                                    Some(ref metadata) => metadata.credits(),
+                                    None => capture::rom_name(&rom_path),
+                                };
+                                display.notify(&format!("Loaded {}", name));
+                                let metadata = metadata.unwrap_or_default();
+                                tickrate = metadata.tickrate.unwrap_or(cpu::INSTRUCTIONS_PER_FRAME);
+                                if options.palette.is_none() {
+                                    display.set_palette(metadata.palette.unwrap_or(PALETTES[palette].1));
+                                }
+                                input.set_key_hints(metadata.keys);
+                            }
+                            Err(why) => report(&mut display, &why),
+                        }
+                    }
+                }
+            }
+            status_changed |= matches!(command, external::Command::ToggleTurbo
+                | external::Command::SlowMotion
+                | external::Command::TogglePause
+                | external::Command::FrameAdvance
+                | external::Command::Step
+                | external::Command::ToggleCounter);
+        }
 
-    while let Ok(keypad) = input.poll() {
         // if keypad < 0 {
         //     chip8_machine.key_pressed = false;
         // } else {
@@ -70,12 +640,93 @@ fn main() {
         //     chip8_machine.key = keypad as u8;
         // }
 
-        let i = chip8_machine.run(keypad);
-        log.log_machine(&chip8_machine, i);
-        if chip8_machine.draw {
-            display.draw(&chip8_machine.screen);
-            chip8_machine.draw = false;
+        // In turbo, frames run back to back until this real frame is over
+        // and only the last one is presented
+        let was_debugged = gdb.as_ref().is_some_and(|g| g.is_halted());
+        match gdb.as_mut().and_then(|g| g.poll(&mut chip8_machine)) {
+            Some(GdbEvent::Attached) => report(&mut display, "Debugger attached"),
+            Some(GdbEvent::Detached) => report(&mut display, "Debugger detached"),
+            None => {}
+        }
+        let debugged = gdb.as_ref().is_some_and(|g| g.is_halted());
+        status_changed |= debugged != was_debugged;
+
+        // Nothing runs while the menu is open or the debugger stopped the machine
+        let running = menu.is_none() && error.is_none() && !debugged;
+        let mut due = if running { pacing.frames_due() } else { 0 };
+        'frames: while due > 0 || (running && pacing.is_unlimited() && frame_start.elapsed() < FRAME_DURATION) {
+            due = due.saturating_sub(1);
+            for _ in 0..tickrate {
+                if let Some(ref mut gdb) = gdb {
+                    if gdb.check_breakpoint(&chip8_machine) {
+                        status_changed = true;
+                        break 'frames;
+                    }
+                }
+                match chip8_machine.run(keypad) {
+                    Ok(i) => {
+                        if let Some(ref mut logger) = log {
+                            if let Err(why) = logger.log_machine(&chip8_machine, i) {
+                                report(&mut display, &format!("Couldn't dump the machine: {}", why));
+                                log = None;
+                            }
+                        }
+                        if let Some(why) = audio.as_ref().and_then(|recording| recording.check_opcode(i).err()) {
+                            report(&mut display, &why);
+                            audio = None;
+                        }
+                    }
+                    Err(why) => {
+                        if let Some(ref mut gdb) = gdb {
+                            gdb.fault();
+                        }
+                        let message = format!("Emulation stopped at {:#05x}: {}", chip8_machine.pc, why);
+                        println!("{}", message);
+                        error = Some(message);
+                        status_changed = true;
+                        break 'frames;
+                    }
+                }
+            }
+            if let Some(ref mut recording) = audio {
+                if let Err(why) = recording.add_frame(chip8_machine.sound_timer > 0) {
+                    println!("{}", why);
+                    audio = None;
+                }
+            }
+            chip8_machine.tick_timers();
+            meter.frame(tickrate as u32);
+
+            if let Some(ref mut recording) = recorder {
+                if let Err(why) = recording.add_frame(&chip8_machine.screen) {
+                    println!("{}", why);
+                    recorder = None;
+                }
+            }
+        }
+
+        if meter.update() || status_changed {
+            display.set_title(&title(&pacing, meter.percent()));
+            let debugged = gdb.as_ref().is_some_and(|g| g.is_halted());
+            display.set_status(status(&pacing, &meter, counter, &error, debugged).as_deref());
+        }
+        display.set_panel(menu.as_ref().map(|m| m.panel()));
+        display.update_debugger(&chip8_machine, &keypad);
+        display.draw(&chip8_machine.screen, chip8_machine.dirty.take());
+
+        if !running || !pacing.is_unlimited() {
+            if let Some(remaining) = FRAME_DURATION.checked_sub(frame_start.elapsed()) {
+                thread::sleep(remaining);
+            }
+        }
+    }
+
+    if let Some(recording) = recorder {
+        stop_recording(recording, &mut display);
+    }
+    if let Some(recording) = audio {
+        if let Err(why) = recording.finish() {
+            println!("{}", why);
         }
-        thread::sleep(sleep_duration);
     }
 }
@@ -0,0 +1,913 @@
+use std::collections::{HashMap, VecDeque};
+
+// Programs start after the interpreter area, like on the COSMAC VIP
+const PROGRAM_START: usize = 0x200;
+const MEMORY_SIZE: usize = 4096;
+// Macros expanding to themselves, directly or not, would never end
+const MAX_MACRO_DEPTH: usize = 64;
+// Macros expanding to several others grow exponentially even when shallow
+const MAX_EXPANDED_TOKENS: usize = 1 << 18;
+
+// Statements of the SCHIP and XO-CHIP extensions, which the machine can't run
+const EXTENSIONS: [&str; 14] = [
+    "hires", "lores", "scroll-down", "scroll-up", "scroll-left", "scroll-right", "exit",
+    "plane", "audio", "pitch", "saveflags", "loadflags", "bighex", "long",
+];
+
+/// Assembles Octo source into a CHIP-8 ROM loaded at 0x200. Covers the
+/// CHIP-8 instructions, labels, `:const`, `:alias`, `:unpack`, `:next`,
+/// `:org`, `:byte`, `:pointer`, `:macro`, `:calc` and the structured
+/// `if`/`begin`/`else`/`end` and `loop`/`while`/`again`. Errors name the
+/// line they were found on.
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut assembler = Assembler::new(source);
+    assembler.run().map_err(|(line, why)| format!("line {}: {}", line, why))?;
+    Ok(assembler.memory[PROGRAM_START..assembler.end].to_vec())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Token {
+    text: String,
+    line: usize,
+    // Macro expansions the token came out of
+    depth: usize,
+}
+
+// Addresses not known yet where a label is used before being defined
+enum Fixup {
+    // Low 12 bits of the instruction at this address
+    Address,
+    // `:unpack`: the nibble goes in the high byte of the first instruction
+    Unpack(u8),
+    // `:pointer`: a big-endian 16-bit word
+    Pointer,
+}
+
+// A value expected by an instruction, labels may be defined later on
+enum Operand {
+    Known(i64),
+    Label(String),
+}
+
+// Condition of `if` and `while`, reduced to a single skip instruction
+enum Condition {
+    Equal(u8, Rhs),
+    NotEqual(u8, Rhs),
+    Key(u8),
+    NotKey(u8),
+}
+
+enum Rhs {
+    Byte(u8),
+    Register(u8),
+}
+
+enum Control {
+    // Jump to patch with the address of the `else` or `end`
+    If(usize),
+    // Start of the loop and the jumps out of it of its `while`s
+    Loop(usize, Vec<usize>),
+}
+
+type Failure = (usize, String);
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    memory: Vec<u8>,
+    pc: usize,
+    // End of the highest byte written
+    end: usize,
+    // The line of the token being assembled, for errors
+    line: usize,
+    depth: usize,
+    expanded: usize,
+    started: bool,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, (Vec<String>, Vec<Token>)>,
+    fixups: Vec<(usize, String, Fixup, usize)>,
+    control: Vec<Control>,
+}
+
+impl Assembler {
+    fn new(source: &str) -> Self {
+        let tokens = source.lines().enumerate()
+            .flat_map(|(i, line)| {
+                let code = line.split('#').next().unwrap_or_default();
+                code.split_whitespace().map(move |t| Token { text: String::from(t), line: i + 1, depth: 0 })
+            })
+            .collect();
+        Assembler {
+            tokens,
+            memory: vec![0; MEMORY_SIZE],
+            pc: PROGRAM_START,
+            end: PROGRAM_START,
+            line: 1,
+            depth: 0,
+            expanded: 0,
+            started: false,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            control: Vec::new(),
+        }
+    }
+
+    fn run(&mut self) -> Result<(), Failure> {
+        while let Some(token) = self.tokens.pop_front() {
+            self.line = token.line;
+            self.depth = token.depth;
+            self.statement(&token.text)?;
+        }
+        if !self.control.is_empty() {
+            return self.fail("a block is missing its end or again");
+        }
+        if !self.labels.contains_key("main") {
+            return self.fail("the program has no main");
+        }
+
+        for (address, label, fixup, line) in std::mem::take(&mut self.fixups) {
+            let target = match self.labels.get(&label) {
+                Some(&target) => target,
+                None => return Err((line, format!("undefined label {}", label))),
+            };
+            match fixup {
+                Fixup::Address => {
+                    self.memory[address] = (self.memory[address] & 0xF0) | (target >> 8) as u8;
+                    self.memory[address + 1] = target as u8;
+                }
+                Fixup::Unpack(nibble) => {
+                    self.memory[address + 1] = (nibble << 4) | (target >> 8) as u8;
+                    self.memory[address + 3] = target as u8;
+                }
+                Fixup::Pointer => {
+                    self.memory[address] = (target >> 8) as u8;
+                    self.memory[address + 1] = target as u8;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: &str) -> Result<(), Failure> {
+        // Octo jumps to main first, unless the program starts with it.
+        // Directives that emit nothing don't count
+        let emits = !matches!(token, ":const" | ":calc" | ":alias" | ":macro" | ":proto" | ":breakpoint" | ":monitor");
+        if !self.started && emits && !self.macros.contains_key(token) {
+            self.started = true;
+            let starts_with_main = token == ":" && self.tokens.front().is_some_and(|t| t.text == "main");
+            if !starts_with_main {
+                self.jump(0x1000, Operand::Label(String::from("main")))?;
+            }
+        }
+
+        match token {
+            ":" => {
+                let name = self.name()?;
+                self.define(name, self.pc)
+            }
+            ":next" => {
+                let name = self.name()?;
+                self.define(name, self.pc + 1)
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.number()?;
+                self.constants.insert(name, value as f64);
+                Ok(())
+            }
+            ":calc" => {
+                let name = self.name()?;
+                self.expect("{")?;
+                let value = self.expression("}")?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+                Ok(())
+            }
+            ":unpack" => {
+                let nibble = self.number()?;
+                if !(0..16).contains(&nibble) {
+                    return self.fail("unpack expects a nibble");
+                }
+                let at = self.pc;
+                match self.operand()? {
+                    Operand::Known(address) => {
+                        let address = self.address(address)?;
+                        self.emit(0x6000 | (nibble as u16) << 4 | address >> 8)?;
+                        self.emit(0x6100 | (address & 0xFF))
+                    }
+                    Operand::Label(label) => {
+                        self.fixups.push((at, label, Fixup::Unpack(nibble as u8), self.line));
+                        self.emit(0x6000)?;
+                        self.emit(0x6100)
+                    }
+                }
+            }
+            ":org" => {
+                let address = self.number()?;
+                self.pc = self.address(address)? as usize;
+                Ok(())
+            }
+            ":byte" => {
+                let value = self.number()?;
+                let byte = self.byte(value)?;
+                self.emit_byte(byte)
+            }
+            ":pointer" => {
+                let at = self.pc;
+                match self.operand()? {
+                    Operand::Known(value) => {
+                        self.emit_byte((value >> 8) as u8)?;
+                        self.emit_byte(value as u8)
+                    }
+                    Operand::Label(label) => {
+                        self.fixups.push((at, label, Fixup::Pointer, self.line));
+                        self.emit_byte(0)?;
+                        self.emit_byte(0)
+                    }
+                }
+            }
+            ":macro" => self.define_macro(),
+            ":call" => {
+                let target = self.operand()?;
+                self.jump(0x2000, target)
+            }
+            ":proto" => self.name().map(|_| ()),
+            ":breakpoint" => self.name().map(|_| ()),
+            ":monitor" => {
+                self.operand()?;
+                self.operand().map(|_| ())
+            }
+            "clear" => self.emit(0x00E0),
+            "return" | ";" => self.emit(0x00EE),
+            "bcd" => self.register_instruction(0xF033),
+            "save" => self.register_instruction(0xF055),
+            "load" => self.register_instruction(0xF065),
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                let n = self.number()?;
+                if !(0..16).contains(&n) {
+                    return self.fail("sprite expects a height of 0 to 15");
+                }
+                self.emit(0xD000 | x << 8 | y << 4 | n as u16)
+            }
+            "jump" => {
+                let target = self.operand()?;
+                self.jump(0x1000, target)
+            }
+            "jump0" => {
+                let target = self.operand()?;
+                self.jump(0xB000, target)
+            }
+            "native" => {
+                let target = self.operand()?;
+                self.jump(0x0000, target)
+            }
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.register()? as u16;
+                self.emit(if token == "delay" { 0xF015 } else { 0xF018 } | x << 8)
+            }
+            "i" => self.index(),
+            "if" => self.conditional(),
+            "else" => match self.control.pop() {
+                Some(Control::If(jump)) => {
+                    let at = self.pc;
+                    self.emit(0x1000)?;
+                    self.patch(jump, self.pc);
+                    self.control.push(Control::If(at));
+                    Ok(())
+                }
+                _ => self.fail("else without if ... begin"),
+            },
+            "end" => match self.control.pop() {
+                Some(Control::If(jump)) => {
+                    self.patch(jump, self.pc);
+                    Ok(())
+                }
+                _ => self.fail("end without if ... begin"),
+            },
+            "loop" => {
+                self.control.push(Control::Loop(self.pc, Vec::new()));
+                Ok(())
+            }
+            "while" => {
+                let condition = self.condition()?;
+                let at = self.skip(condition, false)?;
+                match self.control.last_mut() {
+                    Some(Control::Loop(_, exits)) => exits.push(at),
+                    _ => return self.fail("while outside of a loop"),
+                }
+                self.emit(0x1000)
+            }
+            "again" => match self.control.pop() {
+                Some(Control::Loop(start, exits)) => {
+                    self.emit(0x1000 | start as u16)?;
+                    for exit in exits {
+                        self.patch(exit, self.pc);
+                    }
+                    Ok(())
+                }
+                _ => self.fail("again without loop"),
+            },
+            _ if EXTENSIONS.contains(&token) => self.fail(&format!("{} needs SCHIP or XO-CHIP, which this emulator doesn't run", token)),
+            _ if self.is_register(token) => {
+                let x = self.register_named(token)?;
+                self.assignment(x)
+            }
+            _ if self.macros.contains_key(token) => self.expand(token),
+            _ if token.starts_with(':') => self.fail(&format!("unsupported directive {}", token)),
+            _ => {
+                // Numbers are emitted as bytes, names call subroutines
+                self.push_back(String::from(token));
+                match self.operand()? {
+                    Operand::Known(value) if !self.labels.contains_key(token) => {
+                        let byte = self.byte(value)?;
+                        self.emit_byte(byte)
+                    }
+                    Operand::Known(address) => self.jump(0x2000, Operand::Known(address)),
+                    Operand::Label(label) => self.jump(0x2000, Operand::Label(label)),
+                }
+            }
+        }
+    }
+
+    // `vx op ...` statements
+    fn assignment(&mut self, x: u8) -> Result<(), Failure> {
+        let x = x as u16;
+        let operator = self.next()?;
+        let (register, byte) = match operator.as_str() {
+            ":=" => match self.peek() {
+                Some("key") => return self.skip_token().and_then(|_| self.emit(0xF00A | x << 8)),
+                Some("delay") => return self.skip_token().and_then(|_| self.emit(0xF007 | x << 8)),
+                Some("random") => {
+                    self.skip_token()?;
+                    let mask = self.number()?;
+                    let mask = self.byte(mask)?;
+                    return self.emit(0xC000 | x << 8 | mask as u16);
+                }
+                _ => (0x8000, Some(0x6000)),
+            },
+            "+=" => (0x8004, Some(0x7000)),
+            "-=" => (0x8005, None),
+            "=-" => (0x8007, None),
+            "|=" => (0x8001, None),
+            "&=" => (0x8002, None),
+            "^=" => (0x8003, None),
+            ">>=" => (0x8006, None),
+            "<<=" => (0x800E, None),
+            _ => return self.fail(&format!("unknown operator {}", operator)),
+        };
+
+        let rhs = self.next()?;
+        if self.is_register(&rhs) {
+            let y = self.register_named(&rhs)? as u16;
+            return self.emit(register | x << 8 | y << 4);
+        }
+        self.push_back(rhs);
+        let value = self.number()?;
+        match (operator.as_str(), byte) {
+            // Subtracting a constant adds its two's complement
+            ("-=", _) => {
+                let value = self.byte(value)?;
+                self.emit(0x7000 | x << 8 | value.wrapping_neg() as u16)
+            }
+            (_, Some(opcode)) => {
+                let value = self.byte(value)?;
+                self.emit(opcode | x << 8 | value as u16)
+            }
+            _ => self.fail(&format!("{} expects a register", operator)),
+        }
+    }
+
+    fn index(&mut self) -> Result<(), Failure> {
+        let operator = self.next()?;
+        match operator.as_str() {
+            ":=" if self.peek() == Some("hex") => {
+                self.skip_token()?;
+                self.register_instruction(0xF029)
+            }
+            ":=" if self.peek().is_some_and(|t| EXTENSIONS.contains(&t)) => {
+                let token = self.next()?;
+                self.fail(&format!("i := {} needs SCHIP or XO-CHIP, which this emulator doesn't run", token))
+            }
+            ":=" => {
+                let target = self.operand()?;
+                self.jump(0xA000, target)
+            }
+            "+=" => self.register_instruction(0xF01E),
+            _ => self.fail(&format!("unknown operator i {}", operator)),
+        }
+    }
+
+    // `if <condition> then <statement>` skips the statement unless the
+    // condition holds, `if <condition> begin` jumps to the `else` or `end`
+    fn conditional(&mut self) -> Result<(), Failure> {
+        let condition = self.condition()?;
+        match self.next()?.as_str() {
+            "then" => self.skip(condition, true).map(|_| ()),
+            "begin" => {
+                let at = self.skip(condition, false)?;
+                self.control.push(Control::If(at));
+                self.emit(0x1000)
+            }
+            other => self.fail(&format!("expected then or begin, found {}", other)),
+        }
+    }
+
+    // Comparisons other than equality go through vf, whose subtraction
+    // borrow flag tells the order of the operands
+    fn condition(&mut self) -> Result<Condition, Failure> {
+        let x = self.register()?;
+        let operator = self.next()?;
+        match operator.as_str() {
+            "key" => return Ok(Condition::Key(x)),
+            "-key" => return Ok(Condition::NotKey(x)),
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {}
+            _ => return self.fail(&format!("unknown comparison {}", operator)),
+        }
+
+        let rhs = self.next()?;
+        let rhs = if self.is_register(&rhs) {
+            Rhs::Register(self.register_named(&rhs)?)
+        } else {
+            self.push_back(rhs);
+            let value = self.number()?;
+            Rhs::Byte(self.byte(value)?)
+        };
+
+        let x16 = x as u16;
+        // Whether vf ends up with the borrow of x - rhs or of rhs - x
+        let (subtract, flag) = match operator.as_str() {
+            "==" => return Ok(Condition::Equal(x, rhs)),
+            "!=" => return Ok(Condition::NotEqual(x, rhs)),
+            "<" => (true, 0),
+            ">" => (false, 0),
+            "<=" => (false, 1),
+            _ => (true, 1),
+        };
+        match rhs {
+            Rhs::Register(y) => {
+                self.emit(0x8F00 | x16 << 4)?;
+                self.emit(if subtract { 0x8F05 } else { 0x8F07 } | (y as u16) << 4)?;
+            }
+            Rhs::Byte(n) => {
+                self.emit(0x6F00 | n as u16)?;
+                self.emit(if subtract { 0x8F07 } else { 0x8F05 } | x16 << 4)?;
+            }
+        }
+        Ok(Condition::Equal(0xF, Rhs::Byte(flag)))
+    }
+
+    // Emits the instruction skipping the next one when the condition is
+    // `!holds`, i.e. running it only when it is `holds`. Returns the
+    // address after it
+    fn skip(&mut self, condition: Condition, holds: bool) -> Result<usize, Failure> {
+        let condition = if holds {
+            match condition {
+                Condition::Equal(x, rhs) => Condition::NotEqual(x, rhs),
+                Condition::NotEqual(x, rhs) => Condition::Equal(x, rhs),
+                Condition::Key(x) => Condition::NotKey(x),
+                Condition::NotKey(x) => Condition::Key(x),
+            }
+        } else {
+            condition
+        };
+        let opcode = match condition {
+            Condition::Equal(x, Rhs::Byte(n)) => 0x3000 | (x as u16) << 8 | n as u16,
+            Condition::NotEqual(x, Rhs::Byte(n)) => 0x4000 | (x as u16) << 8 | n as u16,
+            Condition::Equal(x, Rhs::Register(y)) => 0x5000 | (x as u16) << 8 | (y as u16) << 4,
+            Condition::NotEqual(x, Rhs::Register(y)) => 0x9000 | (x as u16) << 8 | (y as u16) << 4,
+            Condition::Key(x) => 0xE09E | (x as u16) << 8,
+            Condition::NotKey(x) => 0xE0A1 | (x as u16) << 8,
+        };
+        self.emit(opcode)?;
+        Ok(self.pc)
+    }
+
+    fn define_macro(&mut self) -> Result<(), Failure> {
+        let name = self.name()?;
+        let mut arguments = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token == "{" {
+                break;
+            }
+            arguments.push(token);
+        }
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.tokens.pop_front().ok_or((self.line, format!("macro {} is missing its }}", name)))?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                break;
+            }
+            body.push(token);
+        }
+        self.macros.insert(name, (arguments, body));
+        Ok(())
+    }
+
+    fn expand(&mut self, name: &str) -> Result<(), Failure> {
+        let (arguments, body) = self.macros[name].clone();
+        let mut values = HashMap::new();
+        for argument in arguments {
+            values.insert(argument, self.next()?);
+        }
+        let (line, depth) = (self.line, self.depth + 1);
+        if depth > MAX_MACRO_DEPTH {
+            return self.fail(&format!("macro {} is nested more than {} deep", name, MAX_MACRO_DEPTH));
+        }
+        self.expanded += body.len();
+        if self.expanded > MAX_EXPANDED_TOKENS {
+            return self.fail(&format!("macro {} expands to too much code", name));
+        }
+        for token in body.into_iter().rev() {
+            let text = values.get(&token.text).cloned().unwrap_or(token.text);
+            self.tokens.push_front(Token { text, line, depth });
+        }
+        Ok(())
+    }
+
+    // Octo evaluates expressions right to left, every binary operator
+    // having the same precedence. The opening brace or parenthesis was
+    // already read, `close` ends the expression
+    fn expression(&mut self, close: &str) -> Result<f64, Failure> {
+        let left = self.term()?;
+        let operator = self.next()?;
+        if operator == close {
+            return Ok(left);
+        }
+        if operator == "}" || operator == ")" {
+            return self.fail(&format!("expected {}, found {}", close, operator));
+        }
+        let right = self.expression(close)?;
+        Ok(match operator.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => ((left as i64) & (right as i64)) as f64,
+            "|" => ((left as i64) | (right as i64)) as f64,
+            "^" => ((left as i64) ^ (right as i64)) as f64,
+            "<<" => ((left as i64) << (right as i64 & 63)) as f64,
+            ">>" => ((left as i64) >> (right as i64 & 63)) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as i64 as f64,
+            ">" => (left > right) as i64 as f64,
+            "<=" => (left <= right) as i64 as f64,
+            ">=" => (left >= right) as i64 as f64,
+            "==" => (left == right) as i64 as f64,
+            "!=" => (left != right) as i64 as f64,
+            _ => return self.fail(&format!("unknown operator {}", operator)),
+        })
+    }
+
+    fn term(&mut self) -> Result<f64, Failure> {
+        let token = self.next()?;
+        let unary: Option<fn(f64) -> f64> = match token.as_str() {
+            "-" => Some(|v| -v),
+            "~" => Some(|v| !(v as i64) as f64),
+            "!" => Some(|v| (v == 0.0) as i64 as f64),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.term()?));
+        }
+        match token.as_str() {
+            "(" => self.expression(")"),
+            "@" => {
+                let address = self.term()? as usize;
+                Ok(self.memory.get(address).copied().unwrap_or(0) as f64)
+            }
+            "HERE" => Ok(self.pc as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ => match self.value_of(&token) {
+                Some(value) => Ok(value),
+                None => self.fail(&format!("unknown value {} in an expression", token)),
+            },
+        }
+    }
+
+    // Numbers, constants and labels defined so far
+    fn value_of(&self, token: &str) -> Option<f64> {
+        if let Some(&value) = self.constants.get(token) {
+            return Some(value);
+        }
+        if let Some(&address) = self.labels.get(token) {
+            return Some(address as f64);
+        }
+        let (negative, digits) = match token.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, token),
+        };
+        let value = if let Some(hex) = digits.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16).ok()?
+        } else if let Some(binary) = digits.strip_prefix("0b") {
+            i64::from_str_radix(binary, 2).ok()?
+        } else {
+            digits.parse::<i64>().ok()?
+        };
+        Some(if negative { -value } else { value } as f64)
+    }
+
+    // A value that may be a label defined further down
+    fn operand(&mut self) -> Result<Operand, Failure> {
+        let token = self.next()?;
+        if token == "{" {
+            return self.expression("}").map(|v| Operand::Known(v.floor() as i64));
+        }
+        match self.value_of(&token) {
+            Some(value) => Ok(Operand::Known(value.floor() as i64)),
+            None if is_name(&token) => Ok(Operand::Label(token)),
+            None => self.fail(&format!("expected a value, found {}", token)),
+        }
+    }
+
+    // A value that has to be known right away
+    fn number(&mut self) -> Result<i64, Failure> {
+        match self.operand()? {
+            Operand::Known(value) => Ok(value),
+            Operand::Label(label) => self.fail(&format!("unknown value {}", label)),
+        }
+    }
+
+    fn byte(&self, value: i64) -> Result<u8, Failure> {
+        if !(-128..=255).contains(&value) {
+            return self.fail(&format!("{} doesn't fit in a byte", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn address(&self, value: i64) -> Result<u16, Failure> {
+        if !(0..MEMORY_SIZE as i64).contains(&value) {
+            return self.fail(&format!("{} isn't an address", value));
+        }
+        Ok(value as u16)
+    }
+
+    fn register_instruction(&mut self, opcode: u16) -> Result<(), Failure> {
+        let x = self.register()? as u16;
+        self.emit(opcode | x << 8)
+    }
+
+    // An instruction with a 12-bit address
+    fn jump(&mut self, opcode: u16, target: Operand) -> Result<(), Failure> {
+        match target {
+            Operand::Known(address) => {
+                let address = self.address(address)?;
+                self.emit(opcode | address)
+            }
+            Operand::Label(label) => {
+                self.fixups.push((self.pc, label, Fixup::Address, self.line));
+                self.emit(opcode)
+            }
+        }
+    }
+
+    fn patch(&mut self, jump: usize, target: usize) {
+        self.memory[jump] = (self.memory[jump] & 0xF0) | (target >> 8) as u8;
+        self.memory[jump + 1] = target as u8;
+    }
+
+    fn define(&mut self, name: String, address: usize) -> Result<(), Failure> {
+        if self.labels.contains_key(&name) {
+            return self.fail(&format!("label {} is defined twice", name));
+        }
+        self.labels.insert(name, address as u16);
+        Ok(())
+    }
+
+    fn emit(&mut self, instruction: u16) -> Result<(), Failure> {
+        self.emit_byte((instruction >> 8) as u8)?;
+        self.emit_byte(instruction as u8)
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), Failure> {
+        if self.pc >= MEMORY_SIZE {
+            return self.fail("the program doesn't fit in memory");
+        }
+        self.memory[self.pc] = byte;
+        self.pc += 1;
+        self.end = self.end.max(self.pc);
+        Ok(())
+    }
+
+    fn is_register(&self, token: &str) -> bool {
+        self.aliases.contains_key(token) || register_index(token).is_some()
+    }
+
+    fn register(&mut self) -> Result<u8, Failure> {
+        let token = self.next()?;
+        self.register_named(&token)
+    }
+
+    fn register_named(&self, token: &str) -> Result<u8, Failure> {
+        match self.aliases.get(token).copied().or_else(|| register_index(token)) {
+            Some(register) => Ok(register),
+            None => self.fail(&format!("expected a register, found {}", token)),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, Failure> {
+        let token = self.next()?;
+        if !is_name(&token) {
+            return self.fail(&format!("{} isn't a valid name", token));
+        }
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), Failure> {
+        let token = self.next()?;
+        if token != expected {
+            return self.fail(&format!("expected {}, found {}", expected, token));
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|t| t.text.as_str())
+    }
+
+    fn skip_token(&mut self) -> Result<(), Failure> {
+        self.next().map(|_| ())
+    }
+
+    // Puts back the token just read, to be read again
+    fn push_back(&mut self, text: String) {
+        self.tokens.push_front(Token { text, line: self.line, depth: self.depth });
+    }
+
+    fn next(&mut self) -> Result<String, Failure> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                self.depth = token.depth;
+                Ok(token.text)
+            }
+            None => self.fail("unexpected end of the program"),
+        }
+    }
+
+    fn fail<T>(&self, why: &str) -> Result<T, Failure> {
+        Err((self.line, String::from(why)))
+    }
+}
+
+fn register_index(token: &str) -> Option<u8> {
+    let digit = token.strip_prefix('v').or_else(|| token.strip_prefix('V'))?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+fn is_name(token: &str) -> bool {
+    token.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instructions_and_labels_assemble() {
+        let rom = assemble("
+            : main
+              clear
+              v0 := 5          # a comment
+              i := digits
+              sprite v0 v1 5
+              v0 += -1
+              v1 -= v2
+              draw
+              jump main
+            : draw
+              i := hex v0
+              return
+            : digits
+              0xF0 0x90
+        ").unwrap();
+        assert_eq!(rom, [
+            0x00, 0xE0, 0x60, 0x05, 0xA2, 0x14, 0xD0, 0x15, 0x70, 0xFF, 0x81, 0x25,
+            0x22, 0x10, 0x12, 0x00, 0xF0, 0x29, 0x00, 0xEE, 0xF0, 0x90,
+        ]);
+    }
+
+    #[test]
+    fn main_is_jumped_to_unless_it_comes_first() {
+        let rom = assemble(": data 1 2 : main jump main").unwrap();
+        assert_eq!(rom, [0x12, 0x04, 0x01, 0x02, 0x12, 0x04]);
+        assert_eq!(assemble(": start clear").unwrap_err(), "line 1: the program has no main");
+    }
+
+    #[test]
+    fn control_flow_compiles_to_skips_and_jumps() {
+        let rom = assemble("
+            : main
+              if v0 == 3 then v1 := 1
+              if v0 key begin
+                v1 := 2
+              else
+                v1 := 3
+              end
+              loop
+                while v2 != v3
+                v2 += 1
+              again
+        ").unwrap();
+        assert_eq!(rom, [
+            0x40, 0x03, 0x61, 0x01,
+            0xE0, 0x9E, 0x12, 0x0C, 0x61, 0x02, 0x12, 0x0E, 0x61, 0x03,
+            0x92, 0x30, 0x12, 0x16, 0x72, 0x01, 0x12, 0x0E,
+        ]);
+
+        // v0 < 5: vf := 5, vf =- v0 gives no borrow when v0 >= 5
+        assert_eq!(assemble(": main if v0 < 5 then clear").unwrap(), [0x6F, 0x05, 0x8F, 0x07, 0x4F, 0x00, 0x00, 0xE0]);
+        assert!(assemble(": main loop clear").is_err());
+    }
+
+    #[test]
+    fn directives_define_values_and_data() {
+        let rom = assemble("
+            :const SPEED 3
+            :calc DOUBLE { SPEED * 2 + 1 }
+            :alias speed v4
+            :macro twice OP { OP OP }
+            : main
+              speed := DOUBLE
+              twice clear
+              :unpack 0xA table
+              :byte { 1 + 1 }
+              :next patched
+              v5 := 0
+            : table
+              :pointer main
+        ").unwrap();
+        assert_eq!(rom, [
+            0x64, 0x09, 0x00, 0xE0, 0x00, 0xE0, 0x60, 0xA2, 0x61, 0x0D,
+            0x02, 0x65, 0x00, 0x02, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn unsupported_programs_are_reported_with_their_line() {
+        assert_eq!(
+            assemble(": main\n  hires").unwrap_err(),
+            "line 2: hires needs SCHIP or XO-CHIP, which this emulator doesn't run"
+        );
+        assert_eq!(assemble(": main\n  jump nowhere").unwrap_err(), "line 2: undefined label nowhere");
+        assert_eq!(assemble(": main\n  v0 := 300").unwrap_err(), "line 2: 300 doesn't fit in a byte");
+    }
+
+    #[test]
+    fn runaway_macros_are_stopped() {
+        let error = assemble(":macro m { m }\n: main\n  m").unwrap_err();
+        assert_eq!(error, "line 3: macro m is nested more than 64 deep");
+
+        // Each level doubles the code, 2^40 tokens for 40 of them
+        let mut source = String::from(":macro m0 { :const x 1 }\n");
+        for level in 1..=40 {
+            source += &format!(":macro m{} {{ m{} m{} }}\n", level, level - 1, level - 1);
+        }
+        source += ": main\n  m40";
+        let error = assemble(&source).unwrap_err();
+        assert!(error.starts_with("line 43: macro m") && error.ends_with("expands to too much code"), "{}", error);
+    }
+
+    #[test]
+    fn expressions_end_with_their_own_delimiter() {
+        assert_eq!(assemble(": main :byte { ( 1 + 2 ) * 3 }").unwrap(), [0x09]);
+        assert_eq!(assemble(": main\n  :byte { 1 + 2 ) }").unwrap_err(), "line 2: expected }, found )");
+        assert_eq!(assemble(": main\n  :byte { ( 1 + 2 }").unwrap_err(), "line 2: expected ), found }");
+    }
+}
@@ -0,0 +1,47 @@
+/// Colors used to show the two states of a pixel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub background: [u8; 3],
+    pub foreground: [u8; 3],
+}
+
+pub const PALETTES: [(&str, Palette); 5] = [
+    ("white", Palette { background: [0x00, 0x00, 0x00], foreground: [0xFF, 0xFF, 0xFF] }),
+    ("green", Palette { background: [0x00, 0x11, 0x00], foreground: [0x33, 0xFF, 0x33] }),
+    ("amber", Palette { background: [0x11, 0x08, 0x00], foreground: [0xFF, 0xB0, 0x00] }),
+    ("lcd", Palette { background: [0x9B, 0xBC, 0x0F], foreground: [0x0F, 0x38, 0x0F] }),
+    ("octo", Palette { background: [0x99, 0x66, 0x00], foreground: [0xFF, 0xCC, 0x00] }),
+];
+
+impl Palette {
+    pub fn by_name(name: &str) -> Option<Palette> {
+        PALETTES.iter().find(|(n, _)| *n == name).map(|&(_, p)| p)
+    }
+
+    /// Parses a `#RRGGBB` color, as used by the ROM database and Octo.
+    pub fn parse_color(color: &str) -> Option<[u8; 3]> {
+        let hex = color.strip_prefix('#')?;
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        Some([channel(0)?, channel(2)?, channel(4)?])
+    }
+
+    /// Color of a pixel lit with `intensity` between 0 (off) and 1 (on).
+    pub fn color(&self, intensity: f32) -> [u8; 3] {
+        let mut rgb = [0; 3];
+        for (c, value) in rgb.iter_mut().enumerate() {
+            let from = self.background[c] as f32;
+            let to = self.foreground[c] as f32;
+            *value = (from + (to - from) * intensity).round() as u8;
+        }
+        rgb
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        PALETTES[0].1
+    }
+}
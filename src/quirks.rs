@@ -0,0 +1,73 @@
+/// Behaviours that differ between CHIP-8 platforms, which ROMs written for
+/// one platform may rely on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    /// 8XY1, 8XY2 and 8XY3 reset VF to 0
+    pub vf_reset: bool,
+    /// FX55 and FX65 leave I pointing after the last register
+    pub memory_increment: bool,
+    /// 8XY6 and 8XYE shift VY into VX instead of shifting VX in place
+    pub shift_vy: bool,
+    /// Sprites are clipped at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+    /// BNNN jumps to XNN + VX instead of NNN + V0
+    pub jump_vx: bool,
+    /// DXYN waits for the next 60 Hz frame before execution continues
+    pub display_wait: bool,
+    /// Subroutine calls that can be nested before 2NNN overflows the stack
+    pub stack_depth: usize,
+}
+
+/// Named presets, the first one being the emulator's own behaviour.
+pub const PRESETS: [(&str, Quirks); 4] = [
+    ("default", Quirks {
+        vf_reset: false,
+        memory_increment: false,
+        shift_vy: false,
+        clip_sprites: false,
+        jump_vx: false,
+        display_wait: false,
+        stack_depth: 16,
+    }),
+    // COSMAC VIP
+    ("chip8", Quirks {
+        vf_reset: true,
+        memory_increment: true,
+        shift_vy: true,
+        clip_sprites: true,
+        jump_vx: false,
+        display_wait: true,
+        stack_depth: 12,
+    }),
+    // SUPER-CHIP 1.1 as found on the HP 48
+    ("schip", Quirks {
+        vf_reset: false,
+        memory_increment: false,
+        shift_vy: false,
+        clip_sprites: true,
+        jump_vx: true,
+        display_wait: false,
+        stack_depth: 16,
+    }),
+    ("xochip", Quirks {
+        vf_reset: false,
+        memory_increment: true,
+        shift_vy: true,
+        clip_sprites: false,
+        jump_vx: false,
+        display_wait: false,
+        stack_depth: 16,
+    }),
+];
+
+impl Default for Quirks {
+    fn default() -> Self {
+        PRESETS[0].1
+    }
+}
+
+impl Quirks {
+    pub fn by_name(name: &str) -> Option<Quirks> {
+        PRESETS.iter().find(|(n, _)| *n == name).map(|&(_, q)| q)
+    }
+}
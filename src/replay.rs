@@ -0,0 +1,60 @@
+/// Keypad input recorded per frame, used to drive the emulator without a
+/// user, e.g. in headless runs on CI.
+///
+/// The text format has one change of the keypad state per line: the frame
+/// it happens at and the pressed keys as a 16 bit hexadecimal mask (bit `n`
+/// is key `n`), which stay pressed until the next line. Empty lines and
+/// lines starting with `#` are ignored:
+///
+/// ```text
+/// # press 5 at frame 60 and release it 10 frames later
+/// 60 0020
+/// 70 0000
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Replay {
+    // Frame and keypad mask, sorted by frame
+    changes: Vec<(u64, u16)>,
+}
+
+impl Replay {
+    pub fn parse(text: &str) -> Result<Replay, String> {
+        let mut changes = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let frame = fields.next().and_then(|f| f.parse::<u64>().ok());
+            let keys = fields.next().and_then(|k| u16::from_str_radix(k.trim_start_matches("0x"), 16).ok());
+            match (frame, keys, fields.next()) {
+                (Some(frame), Some(keys), None) => changes.push((frame, keys)),
+                _ => return Err(format!("Invalid replay line {}: {}", number + 1, line)),
+            }
+        }
+        changes.sort_by_key(|&(frame, _)| frame);
+        Ok(Replay { changes })
+    }
+
+    /// Keypad state during `frame`.
+    pub fn keys_at(&self, frame: u64) -> [bool; 16] {
+        let mask = self.changes
+            .iter()
+            .take_while(|&&(f, _)| f <= frame)
+            .last()
+            .map_or(0, |&(_, keys)| keys);
+
+        let mut keys = [false; 16];
+        for (i, key) in keys.iter_mut().enumerate() {
+            *key = mask & (1 << i) != 0;
+        }
+        keys
+    }
+
+    /// Frame of the last change, after which the input stays the same.
+    pub fn last_frame(&self) -> u64 {
+        self.changes.last().map_or(0, |&(frame, _)| frame)
+    }
+}
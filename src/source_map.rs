@@ -0,0 +1,109 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+/// Where an instruction of the ROM came from in the assembly source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mapping {
+    pub file: PathBuf,
+    /// 1-based
+    pub line: u32,
+    pub address: u16,
+}
+
+/// Maps source lines to ROM addresses and back, for debuggers. Assemblers
+/// write it as JSON, listing the first instruction of each source line:
+///
+/// ```json
+/// {
+///   "mappings": [
+///     { "file": "game.8o", "line": 12, "address": 512 },
+///     { "file": "game.8o", "line": 13, "address": "0x202" }
+///   ]
+/// }
+/// ```
+///
+/// Addresses are numbers or hexadecimal strings. Relative file names are
+/// relative to the directory of the source map.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceMap {
+    mappings: Vec<Mapping>,
+}
+
+impl SourceMap {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = fs::read_to_string(path).map_err(|why| format!("Couldn't read {}: {}", path.display(), why))?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        SourceMap::parse(&json, base).map_err(|why| format!("Couldn't parse {}: {}", path.display(), why))
+    }
+
+    /// Parses a source map, relative file names being taken from `base`.
+    pub fn parse(json: &str, base: &Path) -> Result<Self, String> {
+        let json: Value = serde_json::from_str(json).map_err(|why| why.to_string())?;
+        let entries = json["mappings"].as_array().ok_or("expected a list of mappings")?;
+
+        let mut mappings = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let file = entry["file"].as_str().ok_or("mapping without a file")?;
+            let line = entry["line"].as_u64().ok_or("mapping without a line")?;
+            let address = match entry["address"] {
+                Value::Number(ref n) => n.as_u64(),
+                Value::String(ref s) => u64::from_str_radix(s.trim_start_matches("0x"), 16).ok(),
+                _ => None,
+            };
+            let address = address.filter(|&a| a < 4096).ok_or("mapping without a valid address")?;
+            mappings.push(Mapping { file: base.join(file), line: line as u32, address: address as u16 });
+        }
+        Ok(SourceMap { mappings })
+    }
+
+    /// The first instruction of `line` in `file`, or of the next line with
+    /// code when it has none. `file` matches mappings whose file name it
+    /// ends with, so editors can pass absolute paths.
+    pub fn address_of(&self, file: &Path, line: u32) -> Option<&Mapping> {
+        self.mappings.iter()
+            .filter(|m| m.line >= line && (file.ends_with(&m.file) || m.file.ends_with(file)))
+            .min_by_key(|m| (m.line, m.address))
+    }
+
+    /// The source line of the instruction at `address`, or of the closest
+    /// one before it.
+    pub fn location_of(&self, address: u16) -> Option<&Mapping> {
+        self.mappings.iter()
+            .filter(|m| m.address <= address)
+            .max_by_key(|m| m.address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = r#"{ "mappings": [
+        { "file": "game.8o", "line": 3, "address": 512 },
+        { "file": "game.8o", "line": 4, "address": "0x202" },
+        { "file": "game.8o", "line": 7, "address": "0x206" },
+        { "file": "lib/draw.8o", "line": 1, "address": 520 }
+    ] }"#;
+
+    #[test]
+    fn lines_map_to_addresses() {
+        let map = SourceMap::parse(MAP, Path::new("/src")).unwrap();
+        let address = |file: &str, line| map.address_of(Path::new(file), line).map(|m| (m.line, m.address));
+        assert_eq!(address("/src/game.8o", 4), Some((4, 0x202)));
+        // Blank lines and comments break on the next instruction
+        assert_eq!(address("/src/game.8o", 5), Some((7, 0x206)));
+        assert_eq!(address("game.8o", 8), None);
+        assert_eq!(address("/src/lib/draw.8o", 1), Some((1, 0x208)));
+    }
+
+    #[test]
+    fn addresses_map_to_lines() {
+        let map = SourceMap::parse(MAP, Path::new("/src")).unwrap();
+        let location = map.location_of(0x204).unwrap();
+        assert_eq!((location.file.as_path(), location.line), (Path::new("/src/game.8o"), 4));
+        assert!(map.location_of(0x1FE).is_none());
+        assert!(SourceMap::parse(r#"{ "mappings": [{ "file": "a", "line": 1, "address": 5000 }] }"#, Path::new("")).is_err());
+    }
+}
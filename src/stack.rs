@@ -0,0 +1,52 @@
+/// A subroutine call on the stack: where 2NNN ran and where it went.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    /// Address of the 2NNN instruction, execution resumes after it
+    pub caller: u16,
+    /// Start of the called subroutine
+    pub callee: u16,
+}
+
+/// Subroutine calls being executed, the innermost last. The machine limits
+/// its depth after the platform being emulated, see `Quirks::stack_depth`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stack {
+    frames: Vec<Frame>,
+}
+
+impl Stack {
+    pub fn new() -> Self {
+        Stack { frames: Vec::new() }
+    }
+
+    /// Enters a subroutine, failing when `depth` calls are already nested.
+    pub fn push(&mut self, frame: Frame, depth: usize) -> Result<(), &'static str> {
+        if self.frames.len() >= depth {
+            return Err("Stack overflow");
+        }
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    /// Leaves the innermost subroutine.
+    pub fn pop(&mut self) -> Result<Frame, &'static str> {
+        self.frames.pop().ok_or("Stack underflow")
+    }
+
+    /// Calls being executed, from the outermost to the innermost one.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
@@ -0,0 +1,33 @@
+use chip8::external::{Menu, MenuEvent, MenuInput};
+
+fn menu(items: usize) -> Menu<usize> {
+    (0..items).fold(Menu::new("Test"), |menu, i| menu.action(i, &format!("Item {}", i)))
+}
+
+#[test]
+fn navigation_wraps_and_selects() {
+    let mut m = menu(3).choice(3, "Choice", vec![String::from("a"), String::from("b")], 1);
+    assert_eq!(m.input(MenuInput::Up), None);
+    assert_eq!(m.input(MenuInput::Right), Some(MenuEvent::Changed(3, 0)));
+    assert_eq!(m.choice_of(3), Some(0));
+    assert_eq!(m.input(MenuInput::Down), None);
+    assert_eq!(m.input(MenuInput::Select), Some(MenuEvent::Selected(0)));
+    assert_eq!(m.input(MenuInput::Left), None);
+    assert_eq!(m.input(MenuInput::Back), Some(MenuEvent::Closed));
+    assert_eq!(m.panel().lines[3], "Choice  < a >");
+}
+
+#[test]
+fn long_menus_scroll_to_the_selection() {
+    let mut m = menu(30);
+    for _ in 0..20 {
+        m.input(MenuInput::Down);
+    }
+    let panel = m.panel();
+    let selected = panel.selected.unwrap();
+    assert_eq!(panel.lines[selected], "Item 20");
+    assert!(panel.lines.len() < 30);
+
+    m.input(MenuInput::Up);
+    assert_eq!(m.panel().lines[m.panel().selected.unwrap()], "Item 19");
+}
@@ -0,0 +1,62 @@
+use chip8::cpu::{Chip8Machine, Machine, OpCodes};
+use chip8::instruction::{Chip8Instruction, DecodableInstruction, Opcode};
+use chip8::quirks::PRESETS;
+
+#[test]
+fn decoding_is_total() {
+    for raw in 0..=0xFFFFu16 {
+        let instruction = Chip8Instruction::new((raw >> 8) as u8, raw as u8);
+        assert_eq!(instruction.raw, raw);
+        let [a, b, c, d] = instruction.parts;
+        assert_eq!(((a as u16) << 12) | ((b as u16) << 8) | ((c as u16) << 4) | d as u16, raw);
+    }
+}
+
+#[test]
+fn every_opcode_executes_without_panicking() {
+    for &(_, quirks) in PRESETS.iter() {
+        // Extreme register and index values reach the edges of memory,
+        // the screen and the keypad
+        for &value in [0x00u8, 0xFF].iter() {
+            for &i in [0x000u16, 0xFFF, 0xFFFF].iter() {
+                for raw in 0..=0xFFFFu16 {
+                    let mut m = Machine::new();
+                    m.quirks = quirks;
+                    m.registers = [value; 16];
+                    m.i = i;
+                    m.execute(Opcode::decode(raw));
+                    assert!(m.pc <= 0xFFF);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn running_off_the_end_of_memory_wraps() {
+    // Jump to the last instruction of memory, which sets V0
+    let mut rom = vec![0; 0x1000 - 0x200];
+    rom[..2].copy_from_slice(&[0x1F, 0xFE]);
+    rom[0xDFE..].copy_from_slice(&[0x60, 0x01]);
+
+    let mut m = Machine::new();
+    m.load_rom(&rom).unwrap();
+    m.run([false; 16]).unwrap();
+    assert_eq!(m.pc, 0xFFE);
+    m.run([false; 16]).unwrap();
+    assert_eq!(m.registers[0], 1);
+    assert_eq!(m.pc, 0x000);
+}
+
+#[test]
+fn opcodes_disassemble_to_mnemonics() {
+    let mnemonic = |raw: u16| Opcode::decode(raw).to_string();
+    assert_eq!(mnemonic(0x00E0), "CLS");
+    assert_eq!(mnemonic(0x2A06), "CALL 0xA06");
+    assert_eq!(mnemonic(0x632A), "LD V3, 0x2A");
+    assert_eq!(mnemonic(0x8AB5), "SUB VA, VB");
+    assert_eq!(mnemonic(0xB300), "JP V0, 0x300");
+    assert_eq!(mnemonic(0xD015), "DRW V0, V1, 5");
+    assert_eq!(mnemonic(0xFE65), "LD VE, [I]");
+    assert_eq!(mnemonic(0x5121), "???");
+}
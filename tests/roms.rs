@@ -0,0 +1,104 @@
+use chip8::cpu::{Chip8Machine, Machine, INSTRUCTIONS_PER_FRAME};
+use chip8::quirks::{Quirks, PRESETS};
+
+fn run_frames(rom: &[u8], frames: usize, quirks: Quirks) -> Machine {
+    let mut m = Machine::new();
+    m.quirks = quirks;
+    m.load_rom(rom).unwrap();
+    for _ in 0..frames {
+        for _ in 0..INSTRUCTIONS_PER_FRAME {
+            m.run([false; 16]).unwrap();
+        }
+        m.tick_timers();
+    }
+    m
+}
+
+#[test]
+fn sum_loop() {
+    // V0 = 1 + 2 + ... + 10
+    let rom = [
+        0x60, 0x00, // V0 = 0
+        0x61, 0x01, // V1 = 1
+        0x80, 0x14, // V0 += V1
+        0x71, 0x01, // V1 += 1
+        0x31, 0x0B, // skip if V1 == 11
+        0x12, 0x04, // jump 0x204
+        0x12, 0x0C, // halt
+    ];
+    for &(_, quirks) in PRESETS.iter() {
+        let m = run_frames(&rom, 10, quirks);
+        assert_eq!(m.registers[0], 55);
+        assert_eq!(m.pc, 0x20C);
+    }
+}
+
+#[test]
+fn subroutine_draws_digits() {
+    // Draws the digits 0 to 3 side by side with a subroutine
+    let rom = [
+        0x60, 0x00, // V0 = 0
+        0x61, 0x00, // V1 = 0
+        0x62, 0x00, // V2 = 0
+        0x22, 0x10, // call 0x210
+        0x70, 0x01, // V0 += 1
+        0x30, 0x04, // skip if V0 == 4
+        0x12, 0x06, // jump 0x206
+        0x12, 0x0E, // halt
+        0xF0, 0x29, // I = digit V0
+        0xD1, 0x25, // draw at (V1, V2)
+        0x71, 0x05, // V1 += 5
+        0x00, 0xEE, // return
+    ];
+    // Top rows: 0 is 0xF0, 1 is 0x20, 2 and 3 are 0xF0
+    let expected: Vec<bool> = [0xF0u8, 0x20, 0xF0, 0xF0]
+        .iter()
+        .flat_map(|&row| (0..5).map(move |x| x < 4 && row & (0x80 >> x) != 0))
+        .collect();
+
+    for &(name, quirks) in PRESETS.iter() {
+        let m = run_frames(&rom, 10, quirks);
+        assert_eq!(m.pc, 0x20E, "{}", name);
+        assert!(m.stack.is_empty(), "{}", name);
+        assert_eq!(m.registers[1], 20, "{}", name);
+
+        let top: Vec<bool> = (0..20).map(|x| m.screen[x][0]).collect();
+        assert_eq!(top, expected, "{}", name);
+        assert_eq!(m.registers[0xF], 0, "{}", name);
+    }
+}
+
+#[test]
+fn bcd_round_trip_through_memory() {
+    let rom = [
+        0x60, 0xFE, // V0 = 254
+        0xA3, 0x00, // I = 0x300
+        0xF0, 0x33, // BCD of V0
+        0xF2, 0x65, // load V0..V2
+        0x12, 0x08, // halt
+    ];
+    for &(name, quirks) in PRESETS.iter() {
+        let m = run_frames(&rom, 1, quirks);
+        assert_eq!(m.registers[..3], [2, 5, 4], "{}", name);
+        assert_eq!(m.i, if quirks.memory_increment { 0x303 } else { 0x300 }, "{}", name);
+    }
+}
+
+#[test]
+fn delay_timer_counts_frames() {
+    let rom = [
+        0x60, 0x0A, // V0 = 10
+        0xF0, 0x15, // delay timer = V0
+        0xF1, 0x07, // V1 = delay timer
+        0x31, 0x00, // skip if V1 == 0
+        0x12, 0x04, // jump 0x204
+        0x62, 0x01, // V2 = 1
+        0x12, 0x0C, // halt
+    ];
+    for &(name, quirks) in PRESETS.iter() {
+        let m = run_frames(&rom, 9, quirks);
+        assert_eq!(m.registers[2], 0, "{}", name);
+        let m = run_frames(&rom, 11, quirks);
+        assert_eq!(m.registers[2], 1, "{}", name);
+    }
+}